@@ -0,0 +1,205 @@
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Attribute, Error, FnArg, Ident, ItemFn, LitStr, Pat, Result, Type};
+
+// Turns a function into a tool the model can call.
+//
+// For `fn get_weather(city: String)` this generates a `GetWeatherArgs` struct holding the
+// arguments and a `GetWeatherTool` implementing `openai_utils::ToolFunction`, which knows the
+// definition and how to register the function for dispatch:
+//
+//     /// Looks up the current weather
+//     #[openai_tool]
+//     async fn get_weather(#[arg(description = "name of the city")] city: String) -> String {
+//         ...
+//     }
+//
+//     agent.register_tool::<GetWeatherTool>();
+//
+// The doc comment becomes the description of the function, `name = "..."` and
+// `description = "..."` override the defaults.
+#[proc_macro_attribute]
+pub fn openai_tool(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut options = ToolOptions::default();
+    let parser = syn::meta::parser(|meta| options.parse(meta));
+    parse_macro_input!(attr with parser);
+
+    let function = parse_macro_input!(item as ItemFn);
+    expand(options, function)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+#[derive(Default)]
+struct ToolOptions {
+    name: Option<LitStr>,
+    description: Option<LitStr>,
+}
+
+impl ToolOptions {
+    fn parse(&mut self, meta: syn::meta::ParseNestedMeta) -> Result<()> {
+        if meta.path.is_ident("name") {
+            self.name = Some(meta.value()?.parse()?);
+            Ok(())
+        } else if meta.path.is_ident("description") {
+            self.description = Some(meta.value()?.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("expected `name` or `description`"))
+        }
+    }
+}
+
+struct Argument {
+    ident: Ident,
+    ty: Type,
+    description: Option<LitStr>,
+}
+
+fn expand(options: ToolOptions, mut function: ItemFn) -> Result<TokenStream2> {
+    let signature = &function.sig;
+    if !signature.generics.params.is_empty() || signature.generics.where_clause.is_some() {
+        return Err(Error::new_spanned(
+            &signature.generics,
+            "tool functions cannot be generic",
+        ));
+    }
+
+    let mut arguments = vec![];
+    for input in function.sig.inputs.iter_mut() {
+        let FnArg::Typed(input) = input else {
+            return Err(Error::new_spanned(
+                input,
+                "tool functions cannot take `self`",
+            ));
+        };
+        let Pat::Ident(pattern) = &*input.pat else {
+            return Err(Error::new_spanned(
+                &input.pat,
+                "tool arguments have to be plain identifiers",
+            ));
+        };
+        if let Type::Reference(_) = &*input.ty {
+            return Err(Error::new_spanned(
+                &input.ty,
+                "tool arguments have to be owned",
+            ));
+        }
+
+        let description = take_description(&mut input.attrs)?;
+        arguments.push(Argument {
+            ident: pattern.ident.clone(),
+            ty: (*input.ty).clone(),
+            description,
+        });
+    }
+
+    let vis = &function.vis;
+    let fn_ident = &function.sig.ident;
+    let pascal = to_pascal_case(&fn_ident.to_string());
+    let args_ident = format_ident!("{}Args", pascal);
+    let tool_ident = format_ident!("{}Tool", pascal);
+
+    let name = options
+        .name
+        .unwrap_or_else(|| LitStr::new(&fn_ident.to_string(), Span::call_site()));
+    let with_description = options
+        .description
+        .map(|description| quote!(.with_description(#description)));
+
+    // the doc comment of the function describes the arguments struct, which is where
+    // `Function::new` takes the description from
+    let docs: Vec<&Attribute> = function
+        .attrs
+        .iter()
+        .filter(|attr| attr.path().is_ident("doc"))
+        .collect();
+
+    let fields = arguments.iter().map(|argument| {
+        let Argument {
+            ident,
+            ty,
+            description,
+        } = argument;
+        let doc = description.as_ref().map(|d| quote!(#[doc = #d]));
+        quote!(#doc #vis #ident: #ty)
+    });
+    let idents: Vec<&Ident> = arguments.iter().map(|argument| &argument.ident).collect();
+
+    let register = match function.sig.asyncness {
+        Some(_) => quote!(register_async_with),
+        None => quote!(register_with),
+    };
+
+    Ok(quote! {
+        #function
+
+        #(#docs)*
+        #[derive(::openai_utils::__private::serde::Deserialize, ::openai_utils::__private::schemars::JsonSchema)]
+        #[serde(crate = "::openai_utils::__private::serde")]
+        #[schemars(crate = "::openai_utils::__private::schemars")]
+        #vis struct #args_ident {
+            #(#fields,)*
+        }
+
+        #[derive(Debug, Clone, Copy, Default)]
+        #vis struct #tool_ident;
+
+        impl ::openai_utils::ToolFunction for #tool_ident {
+            fn definition() -> ::openai_utils::Function {
+                ::openai_utils::Function::new::<#args_ident>(#name) #with_description
+            }
+
+            fn register(registry: &mut ::openai_utils::FunctionRegistry) -> ::openai_utils::Function {
+                registry.#register(
+                    <Self as ::openai_utils::ToolFunction>::definition(),
+                    |args: #args_ident| #fn_ident(#(args.#idents),*),
+                )
+            }
+        }
+    })
+}
+
+// removes `#[arg(description = "...")]` from an argument, doc comments aren't allowed there
+fn take_description(attrs: &mut Vec<Attribute>) -> Result<Option<LitStr>> {
+    let mut description = None;
+    let mut error = None;
+
+    attrs.retain(|attr| {
+        if !attr.path().is_ident("arg") {
+            return true;
+        }
+
+        let result = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("description") {
+                description = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("expected `description`"))
+            }
+        });
+        if let Err(e) = result {
+            error = Some(e);
+        }
+        false
+    });
+
+    match error {
+        Some(e) => Err(e),
+        None => Ok(description),
+    }
+}
+
+fn to_pascal_case(name: &str) -> String {
+    name.split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
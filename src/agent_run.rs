@@ -0,0 +1,351 @@
+use std::fmt;
+
+use crate::error::{InternalError, UtilsResult};
+use crate::{AiAgent, Chat, Client, Message, Usage, DEFAULT_CLIENT};
+
+#[derive(Debug, Clone)]
+pub enum RunStep {
+    // a response of the model, its message has already been appended to the agent
+    Completion(Chat),
+
+    // the results of the calls the model asked for in the previous completion
+    FunctionResults(Vec<Message>),
+}
+
+#[derive(Debug, Clone)]
+pub struct RunTranscript {
+    pub steps: Vec<RunStep>,
+    pub final_message: Message,
+    pub usage: Usage,
+}
+
+type StepHook = Box<dyn FnMut(&RunStep) + Send>;
+
+pub struct RunOptions {
+    pub max_iterations: usize,
+    hooks: Vec<StepHook>,
+}
+
+impl RunOptions {
+    pub fn new() -> Self {
+        Self {
+            max_iterations: 10,
+            hooks: vec![],
+        }
+    }
+
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    // called with every step as soon as it happened
+    pub fn on_step(mut self, hook: impl FnMut(&RunStep) + Send + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    fn record(&mut self, steps: &mut Vec<RunStep>, step: RunStep) {
+        self.hooks.iter_mut().for_each(|hook| hook(&step));
+        steps.push(step);
+    }
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for RunOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RunOptions")
+            .field("max_iterations", &self.max_iterations)
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
+
+impl AiAgent {
+    pub async fn run(&mut self) -> UtilsResult<RunTranscript> {
+        self.run_with(&DEFAULT_CLIENT, RunOptions::default()).await
+    }
+
+    // calls the model, executes the functions it asks for and feeds the results back until
+    // it answers without calling anything. every iteration is one completion.
+    pub async fn run_with(
+        &mut self,
+        client: &Client,
+        mut options: RunOptions,
+    ) -> UtilsResult<RunTranscript> {
+        let mut steps = vec![];
+        let mut usage = Usage::default();
+
+        for _ in 0..options.max_iterations {
+            let chat = self.create_with(client).await?;
+            usage.prompt_tokens += chat.usage.prompt_tokens;
+            usage.completion_tokens += chat.usage.completion_tokens;
+            usage.total_tokens += chat.usage.total_tokens;
+
+            let message = chat
+                .choices
+                .first()
+                .ok_or(InternalError::NoChoices)?
+                .message
+                .clone();
+            self.push_message(message.clone());
+            options.record(&mut steps, RunStep::Completion(chat));
+
+            // some servers send an empty list instead of leaving the tool calls out
+            if message.function_call.is_none()
+                && message.tool_calls.as_ref().is_none_or(Vec::is_empty)
+            {
+                return Ok(RunTranscript {
+                    steps,
+                    final_message: message,
+                    usage,
+                });
+            }
+
+            // failures are reported to the model as the result instead of ending the run, so
+            // it gets a chance to correct itself
+            let (results, _) = self.execute_calls(&message).await;
+            self.messages.extend(results.iter().cloned());
+            options.record(&mut steps, RunStep::FunctionResults(results));
+        }
+
+        Err(InternalError::MaxIterationsExceeded(options.max_iterations))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::test_server::{Reply, TestServer};
+    use serde_json::{json, Value};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(serde_derive::Deserialize, schemars::JsonSchema)]
+    struct AddArgs {
+        a: i64,
+        b: i64,
+    }
+
+    fn completion(message: Value, prompt_tokens: u64, completion_tokens: u64) -> Reply {
+        Reply::json(
+            200,
+            &json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt-4o",
+                "choices": [{"index": 0, "message": message, "finish_reason": "stop"}],
+                "usage": {
+                    "prompt_tokens": prompt_tokens,
+                    "completion_tokens": completion_tokens,
+                    "total_tokens": prompt_tokens + completion_tokens,
+                },
+            }),
+        )
+    }
+
+    fn answer(content: &str) -> Reply {
+        completion(json!({"role": "assistant", "content": content}), 10, 5)
+    }
+
+    fn tool_calls(calls: &[(&str, &str, &str)]) -> Reply {
+        let calls: Vec<Value> = calls
+            .iter()
+            .map(|(id, name, arguments)| {
+                json!({"id": id, "type": "function", "function": {"name": name, "arguments": arguments}})
+            })
+            .collect();
+        completion(
+            json!({"role": "assistant", "content": null, "tool_calls": calls}),
+            10,
+            5,
+        )
+    }
+
+    fn agent() -> AiAgent {
+        let mut agent = AiAgent::new("gpt-4o");
+        agent.register_function(|args: AddArgs| args.a + args.b, "add");
+        agent.push_message(Message::new("user").with_content("what is 1 + 2?"));
+        agent
+    }
+
+    // the messages the model was sent in the nth request
+    fn sent_messages(server: &TestServer, request: usize) -> Vec<Value> {
+        server.requests()[request]["messages"]
+            .as_array()
+            .unwrap()
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn runs_tool_calls_until_the_model_answers() {
+        let server = TestServer::start(vec![
+            tool_calls(&[("call_1", "add", r#"{"a":1,"b":2}"#)]),
+            answer("1 + 2 = 3"),
+        ])
+        .await;
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook_seen = seen.clone();
+        let options = RunOptions::new().on_step(move |step| {
+            hook_seen.lock().unwrap().push(match step {
+                RunStep::Completion(_) => "completion",
+                RunStep::FunctionResults(_) => "results",
+            })
+        });
+
+        let mut agent = agent();
+        let transcript = agent.run_with(&server.client(), options).await.unwrap();
+
+        assert_eq!(
+            transcript.final_message.content.unwrap().text(),
+            "1 + 2 = 3"
+        );
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec!["completion", "results", "completion"]
+        );
+        assert_eq!(transcript.steps.len(), 3);
+        match &transcript.steps[1] {
+            RunStep::FunctionResults(results) => {
+                assert_eq!(results.len(), 1);
+                assert_eq!(results[0].tool_call_id.as_deref(), Some("call_1"));
+                assert_eq!(results[0].content.as_ref().unwrap().text(), "3");
+            }
+            step => panic!("expected function results, got {:?}", step),
+        }
+
+        assert_eq!(transcript.usage.prompt_tokens, 20);
+        assert_eq!(transcript.usage.completion_tokens, 10);
+        assert_eq!(transcript.usage.total_tokens, 30);
+
+        // the second request carries the call and its result
+        let messages = sent_messages(&server, 1);
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["tool_calls"][0]["id"], "call_1");
+        assert_eq!(messages[2]["role"], "tool");
+        assert_eq!(messages[2]["tool_call_id"], "call_1");
+        assert_eq!(messages[2]["content"], "3");
+
+        let roles: Vec<&str> = agent.messages.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec!["user", "assistant", "tool", "assistant"]);
+    }
+
+    #[tokio::test]
+    async fn runs_legacy_function_calls() {
+        let server = TestServer::start(vec![
+            completion(
+                json!({"role": "assistant", "content": null, "function_call": {"name": "add", "arguments": r#"{"a":2,"b":2}"#}}),
+                10,
+                5,
+            ),
+            answer("4"),
+        ])
+        .await;
+
+        let transcript = agent()
+            .run_with(&server.client(), RunOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(transcript.steps.len(), 3);
+
+        let messages = sent_messages(&server, 1);
+        assert_eq!(messages[2]["role"], "function");
+        assert_eq!(messages[2]["name"], "add");
+        assert_eq!(messages[2]["content"], "4");
+    }
+
+    #[tokio::test]
+    async fn empty_tool_calls_end_the_run() {
+        let server = TestServer::start(vec![completion(
+            json!({"role": "assistant", "content": "done", "tool_calls": []}),
+            10,
+            5,
+        )])
+        .await;
+
+        let transcript = agent()
+            .run_with(&server.client(), RunOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(transcript.steps.len(), 1);
+        assert_eq!(transcript.final_message.content.unwrap().text(), "done");
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stops_after_max_iterations() {
+        let server = TestServer::start(vec![
+            tool_calls(&[("call_1", "add", r#"{"a":1,"b":2}"#)]),
+            tool_calls(&[("call_2", "add", r#"{"a":3,"b":4}"#)]),
+            answer("never asked for"),
+        ])
+        .await;
+
+        let options = RunOptions::new().with_max_iterations(2);
+        let e = agent()
+            .run_with(&server.client(), options)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            Error::Internal(InternalError::MaxIterationsExceeded(2))
+        ));
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn feeds_tool_errors_back_to_the_model() {
+        let server = TestServer::start(vec![
+            tool_calls(&[
+                ("call_1", "subtract", r#"{"a":1,"b":2}"#),
+                ("call_2", "add", r#"{"a":"one","b":2}"#),
+            ]),
+            tool_calls(&[("call_3", "add", r#"{"a":1,"b":2}"#)]),
+            answer("3"),
+        ])
+        .await;
+
+        let transcript = agent()
+            .run_with(&server.client(), RunOptions::new())
+            .await
+            .unwrap();
+        assert_eq!(transcript.steps.len(), 5);
+
+        let messages = sent_messages(&server, 1);
+        assert_eq!(messages[2]["tool_call_id"], "call_1");
+        assert!(messages[2]["content"]
+            .as_str()
+            .unwrap()
+            .starts_with("error: "));
+        assert_eq!(messages[3]["tool_call_id"], "call_2");
+        assert!(messages[3]["content"]
+            .as_str()
+            .unwrap()
+            .starts_with("The arguments for `add` are invalid"));
+
+        let messages = sent_messages(&server, 2);
+        assert_eq!(messages.last().unwrap()["content"], "3");
+    }
+
+    #[tokio::test]
+    async fn api_errors_end_the_run() {
+        let server = TestServer::start(vec![Reply::json(
+            400,
+            &json!({"error": {"message": "bad request", "type": "invalid_request_error", "param": null, "code": null}}),
+        )])
+        .await;
+
+        let e = agent()
+            .run_with(&server.client(), RunOptions::new())
+            .await
+            .unwrap_err();
+        assert_eq!(e.status(), Some(400));
+    }
+}
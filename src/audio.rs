@@ -0,0 +1,179 @@
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::{InternalError, UtilsResult};
+
+// How the model should answer when `modalities` includes "audio".
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AudioConfig {
+    // e.g. "alloy", "echo" or "shimmer"
+    pub voice: String,
+
+    // e.g. "wav", "mp3", "flac", "opus" or "pcm16", streaming only supports "pcm16"
+    pub format: String,
+}
+
+impl AudioConfig {
+    pub fn new(voice: impl Into<String>, format: impl Into<String>) -> Self {
+        Self {
+            voice: voice.into(),
+            format: format.into(),
+        }
+    }
+}
+
+// Audio sent to the model as part of a message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputAudio {
+    // base64 encoded audio
+    pub data: String,
+
+    // "wav" or "mp3"
+    pub format: String,
+}
+
+impl InputAudio {
+    pub fn new(data: impl Into<String>, format: impl Into<String>) -> Self {
+        Self {
+            data: data.into(),
+            format: format.into(),
+        }
+    }
+
+    // the format is read from the data itself
+    pub fn from_bytes(bytes: &[u8]) -> UtilsResult<Self> {
+        let format = audio_format(bytes).ok_or_else(|| {
+            InternalError::ConfigurationError(
+                "unsupported audio format, expected wav or mp3".to_string(),
+            )
+        })?;
+
+        Ok(Self::new(STANDARD.encode(bytes), format))
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> UtilsResult<Self> {
+        let bytes = std::fs::read(path).map_err(InternalError::IoError)?;
+        Self::from_bytes(&bytes)
+    }
+}
+
+// The spoken answer of the model. Later turns only need the id to refer back to it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MessageAudio {
+    pub id: String,
+
+    // base64 encoded audio in the requested format
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+
+    // unix timestamp after which the id can't be referred to anymore
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub transcript: Option<String>,
+}
+
+impl MessageAudio {
+    // the form the api expects when the message is sent back as part of the conversation
+    pub fn reference(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            ..Default::default()
+        }
+    }
+
+    // adds a streamed chunk, the data and transcript arrive in pieces
+    pub fn push(&mut self, delta: &AudioDelta) {
+        if let Some(id) = &delta.id {
+            self.id = id.clone();
+        }
+
+        if let Some(expires_at) = delta.expires_at {
+            self.expires_at = Some(expires_at);
+        }
+
+        if let Some(data) = &delta.data {
+            self.data.get_or_insert_with(String::new).push_str(data);
+        }
+
+        if let Some(transcript) = &delta.transcript {
+            self.transcript
+                .get_or_insert_with(String::new)
+                .push_str(transcript);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AudioDelta {
+    pub id: Option<String>,
+    pub data: Option<String>,
+    pub expires_at: Option<u64>,
+    pub transcript: Option<String>,
+}
+
+fn audio_format(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some("wav"),
+        [b'I', b'D', b'3', ..] => Some("mp3"),
+        [0xFF, b, ..] if is_mp3_frame(*b) => Some("mp3"),
+        _ => None,
+    }
+}
+
+// the byte after 0xFF of an mpeg frame header: the rest of the sync, a valid version and
+// layer III. adts aac shares the sync but always has the layer bits set to 0.
+fn is_mp3_frame(b: u8) -> bool {
+    let version = (b >> 3) & 0x03;
+    let layer = (b >> 1) & 0x03;
+    b & 0xE0 == 0xE0 && version != 0x01 && layer == 0x01
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_wav() {
+        let mut wav = b"RIFF".to_vec();
+        wav.extend(36u32.to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        assert_eq!(audio_format(&wav), Some("wav"));
+    }
+
+    #[test]
+    fn detects_mp3() {
+        assert_eq!(audio_format(b"ID3\x04\x00"), Some("mp3"));
+        // mpeg 1 layer III
+        assert_eq!(audio_format(&[0xFF, 0xFB, 0x90, 0x64]), Some("mp3"));
+        // mpeg 2 layer III
+        assert_eq!(audio_format(&[0xFF, 0xF3, 0x90, 0x64]), Some("mp3"));
+        // mpeg 2.5 layer III
+        assert_eq!(audio_format(&[0xFF, 0xE3, 0x90, 0x64]), Some("mp3"));
+    }
+
+    #[test]
+    fn rejects_aac_and_other_mpeg_layers() {
+        // adts aac, mpeg 4 and mpeg 2
+        assert_eq!(audio_format(&[0xFF, 0xF1, 0x50, 0x80]), None);
+        assert_eq!(audio_format(&[0xFF, 0xF9, 0x50, 0x80]), None);
+        // mpeg 1 layer II and layer I
+        assert_eq!(audio_format(&[0xFF, 0xFD, 0x90, 0x64]), None);
+        assert_eq!(audio_format(&[0xFF, 0xFF, 0x90, 0x64]), None);
+        // reserved version
+        assert_eq!(audio_format(&[0xFF, 0xEB, 0x90, 0x64]), None);
+    }
+
+    #[test]
+    fn rejects_unknown_data() {
+        assert_eq!(audio_format(b"OggS"), None);
+        assert_eq!(audio_format(b"fLaC"), None);
+        assert_eq!(audio_format(&[0xFF]), None);
+        assert_eq!(audio_format(&[]), None);
+        assert!(InputAudio::from_bytes(&[0xFF, 0xF1, 0x50, 0x80]).is_err());
+    }
+}
@@ -1,18 +1,24 @@
 #![allow(dead_code)]
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use crate::{AiAgent, calculate_message_tokens, ChatDelta, Choice, FunctionCall, Message, Usage};
+use crate::{AiAgent, calculate_message_tokens, ChatDelta, Choice, Content, Delta, MessageAudio, FunctionCall, Message, ToolCall, ToolCallDelta, Usage};
+use futures::Stream;
+use futures_util::stream::{self, BoxStream};
 use futures_util::StreamExt;
 use log::trace;
 use reqwest_eventsource::Event;
 
-use crate::chat_completion_request::serialize;
-use crate::error::{InternalError, UtilsResult};
+use crate::chat_completion_request::{error_from_event_source, serialize};
+use crate::error::{InternalError, TimeoutKind, UtilsResult};
 use crate::{Chat, ChoiceDelta};
 use reqwest_eventsource::EventSource;
 use serde_derive::{Deserialize, Serialize};
-use tokio::sync::mpsc::{Receiver, Sender};
+use tokio::sync::{mpsc, watch};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatCompletionDelta {
@@ -23,35 +29,246 @@ pub struct ChatCompletionDelta {
     pub choices: Vec<ChoiceDelta>,
 }
 
-pub struct DeltaReceiver<'a> {
-    pub receiver: Receiver<UtilsResult<ChatDelta>>,
-    pub builder: &'a AiAgent,
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamTimeouts {
+    // until the response headers arrive
+    pub connect: Option<Duration>,
+
+    // from the response headers to the first delta
+    pub first_byte: Option<Duration>,
+
+    // between two deltas
+    pub idle: Option<Duration>,
+}
+
+impl StreamTimeouts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_connect(mut self, connect: Duration) -> Self {
+        self.connect = Some(connect);
+        self
+    }
+
+    pub fn with_first_byte(mut self, first_byte: Duration) -> Self {
+        self.first_byte = Some(first_byte);
+        self
+    }
+
+    pub fn with_idle(mut self, idle: Duration) -> Self {
+        self.idle = Some(idle);
+        self
+    }
+}
+
+// Stops a stream from anywhere, e.g. another task. Cancelling ends the stream without an error,
+// deltas received so far are kept. Streams opened by an agent close their connection right
+// away, even if nobody polls the receiver anymore.
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self {
+            sender: Arc::new(watch::channel(false).0),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    pub async fn cancelled(&self) {
+        let mut receiver = self.sender.subscribe();
+        // the sender lives as long as self, so this can't fail
+        let _ = receiver.wait_for(|cancelled| *cancelled).await;
+    }
+}
+
+impl Default for CancelHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Reassembles tool calls from their streamed fragments. Parallel calls arrive interleaved and
+// are told apart by their index, the first fragment of every call carries its id and name.
+#[derive(Debug, Clone, Default)]
+pub struct ToolCallAccumulator {
+    calls: Vec<ToolCall>,
+    // position in `calls` for every index seen so far
+    indices: BTreeMap<usize, usize>,
+}
+
+impl ToolCallAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, delta: &ToolCallDelta) {
+        let position = match delta.index {
+            // a new id on a known index means the server reused the index for another call
+            Some(index) => match self.indices.get(&index) {
+                Some(&position) if !self.is_new_call(position, delta) => position,
+                _ => {
+                    self.calls.push(empty_tool_call());
+                    self.indices.insert(index, self.calls.len() - 1);
+                    self.calls.len() - 1
+                }
+            },
+            // without an index, an id starts a new call and everything else continues the last one
+            None => match self.calls.len() {
+                len if len > 0 && !self.is_new_call(len - 1, delta) => len - 1,
+                _ => {
+                    self.calls.push(empty_tool_call());
+                    self.calls.len() - 1
+                }
+            },
+        };
+
+        let call = &mut self.calls[position];
+        if let Some(id) = &delta.id {
+            call.id = id.clone();
+        }
+
+        if let Some(tool_type) = &delta.tool_type {
+            call.tool_type = tool_type.clone();
+        }
+
+        if let Some(function) = &delta.function {
+            if let Some(name) = function.name.as_ref().filter(|name| !name.is_empty()) {
+                call.function.name = name.clone();
+            }
+
+            if let Some(arguments) = &function.arguments {
+                call.function.arguments.push_str(arguments);
+            }
+        }
+    }
+
+    fn is_new_call(&self, position: usize, delta: &ToolCallDelta) -> bool {
+        let id = &self.calls[position].id;
+        matches!(&delta.id, Some(new_id) if !id.is_empty() && new_id != id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    pub fn calls(&self) -> &[ToolCall] {
+        &self.calls
+    }
+
+    pub fn into_calls(self) -> Vec<ToolCall> {
+        self.calls
+    }
+}
+
+fn empty_tool_call() -> ToolCall {
+    ToolCall {
+        id: String::new(),
+        tool_type: "function".to_string(),
+        function: FunctionCall {
+            name: String::new(),
+            arguments: String::new(),
+        },
+    }
+}
+
+// how many deltas a spawned stream reads ahead of the receiver
+const DELTA_BUFFER: usize = 32;
+
+// Every delta that passes through, whether polled as a `Stream` or through the `receive*`
+// methods, is kept in `deltas` so the full chat can be constructed afterwards. The receiver
+// owns everything it needs, so it can outlive the agent, e.g. when forwarded from a handler.
+pub struct DeltaReceiver {
+    pub stream: BoxStream<'static, UtilsResult<ChatDelta>>,
+    pub builder: AiAgent,
     pub deltas: Vec<ChatCompletionDelta>,
     usage: usize,
+    cancel: CancelHandle,
 }
 
-impl<'a> DeltaReceiver<'a> {
-    pub fn from(receiver: Receiver<UtilsResult<ChatDelta>>, builder: &'a AiAgent, usage: usize) -> Self {
+impl DeltaReceiver {
+    pub fn from(
+        stream: impl Stream<Item = UtilsResult<ChatDelta>> + Send + 'static,
+        builder: AiAgent,
+        usage: usize,
+    ) -> Self {
+        Self::with_cancel_handle(stream, builder, usage, CancelHandle::new())
+    }
+
+    // reads the event source on its own task, so a cancel or dropping the receiver closes the
+    // connection at once instead of on the next poll
+    pub(crate) fn spawn(es: EventSource, timeouts: StreamTimeouts, builder: AiAgent, usage: usize) -> Self {
+        let cancel = CancelHandle::new();
+        let cancelled = cancel.clone();
+        let (sender, mut receiver) = mpsc::channel(DELTA_BUFFER);
+
+        tokio::spawn(async move {
+            let mut deltas = delta_stream(es, timeouts).boxed();
+            let forward = async {
+                while let Some(delta) = deltas.next().await {
+                    if sender.send(delta).await.is_err() {
+                        break;
+                    }
+                }
+            };
+
+            // whichever finishes first drops the event source and with it the connection
+            tokio::select! {
+                _ = forward => {}
+                _ = cancelled.cancelled() => {}
+                _ = sender.closed() => {}
+            }
+        });
+
+        let stream = stream::poll_fn(move |cx| receiver.poll_recv(cx));
+        Self::with_cancel_handle(stream, builder, usage, cancel)
+    }
+
+    fn with_cancel_handle(
+        stream: impl Stream<Item = UtilsResult<ChatDelta>> + Send + 'static,
+        builder: AiAgent,
+        usage: usize,
+        cancel: CancelHandle,
+    ) -> Self {
+        let cancelled = cancel.clone();
+
         Self {
-            receiver,
+            stream: stream
+                .take_until(async move { cancelled.cancelled().await })
+                .boxed(),
             builder,
             deltas: Vec::new(),
-            usage
+            usage,
+            cancel,
         }
     }
 
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
     pub async fn receive(
         &mut self,
         choice_index: i64,
     ) -> anyhow::Result<Option<ChatCompletionDelta>> {
         loop {
-            if let Some(delta) = self.receiver.recv().await {
+            if let Some(delta) = self.next().await {
                 let delta = delta?;
-                self.deltas.push(delta.clone());
-                for choice in &delta.choices {
-                    if choice.index == choice_index {
-                        continue;
-                    }
+                if delta.choices.iter().any(|choice| choice.index == choice_index) {
                     return Ok(Some(delta));
                 }
             } else {
@@ -62,9 +279,8 @@ impl<'a> DeltaReceiver<'a> {
 
     pub async fn receive_content(&mut self, choice_index: i64) -> anyhow::Result<Option<String>> {
         loop {
-            if let Some(delta) = self.receiver.recv().await {
+            if let Some(delta) = self.next().await {
                 let delta = delta?;
-                self.deltas.push(delta.clone());
                 for choice in &delta.choices {
                     if choice.index != choice_index {
                         continue;
@@ -80,24 +296,67 @@ impl<'a> DeltaReceiver<'a> {
     }
 
     pub async fn receive_all(&mut self) -> anyhow::Result<Option<ChatCompletionDelta>> {
-        if let Some(delta) = self.receiver.recv().await {
-            let delta = delta?;
-            self.deltas.push(delta.clone());
-            Ok(Some(delta))
+        if let Some(delta) = self.next().await {
+            Ok(Some(delta?))
         } else {
             Ok(None)
         }
     }
 
+    // the legacy function call of a choice as far as it has been received, see
+    // `FunctionCall::partial_arguments` for a look at the incomplete arguments
+    pub fn partial_function_call(&self, choice_index: i64) -> Option<FunctionCall> {
+        let mut function_call: Option<FunctionCall> = None;
+        for call in self.choice_deltas(choice_index).filter_map(|delta| delta.function_call.as_ref()) {
+            let function_call = function_call.get_or_insert_with(|| FunctionCall {
+                name: String::new(),
+                arguments: String::new(),
+            });
+            if let Some(name) = &call.name {
+                function_call.name = name.clone();
+            }
+            if let Some(arguments) = &call.arguments {
+                function_call.arguments.push_str(arguments);
+            }
+        }
+        function_call
+    }
+
+    // the tool calls of a choice as far as they have been received
+    pub fn partial_tool_calls(&self, choice_index: i64) -> Vec<ToolCall> {
+        let mut tool_calls = ToolCallAccumulator::new();
+        for call in self.choice_deltas(choice_index).flat_map(|delta| delta.tool_calls.iter().flatten()) {
+            tool_calls.push(call);
+        }
+        tool_calls.into_calls()
+    }
+
+    // the spoken answer of a choice as far as it has been received
+    pub fn partial_audio(&self, choice_index: i64) -> Option<MessageAudio> {
+        let mut audio: Option<MessageAudio> = None;
+        for delta in self.choice_deltas(choice_index).filter_map(|delta| delta.audio.as_ref()) {
+            audio.get_or_insert_with(MessageAudio::default).push(delta);
+        }
+        audio
+    }
+
+    fn choice_deltas(&self, choice_index: i64) -> impl Iterator<Item = &Delta> {
+        self.deltas
+            .iter()
+            .flat_map(|delta| &delta.choices)
+            .filter(move |choice| choice.index == choice_index)
+            .map(|choice| &choice.delta)
+    }
+
     pub async fn construct_chat(&mut self) -> anyhow::Result<Chat> {
         // make sure you get the full response first
         while let Some(delta) = self.receive_all().await? {
-            if delta.choices[0].finish_reason.is_some() {
+            if delta.choices.iter().any(|choice| choice.finish_reason.is_some()) {
                 break;
             }
         }
 
-        if self.deltas.len() == 0 {
+        if self.deltas.is_empty() {
             Err(InternalError::NoDeltasReceived)?
         }
 
@@ -107,12 +366,12 @@ impl<'a> DeltaReceiver<'a> {
             .flat_map(|delta| delta.choices.clone())
             .collect();
 
-        let mut choices_map: HashMap<i64, Vec<ChoiceDelta>> = Default::default();
+        let mut choices_map: BTreeMap<i64, Vec<ChoiceDelta>> = Default::default();
         choice_list.into_iter().for_each(|choice| {
             choices_map.entry(choice.index).or_default().push(choice);
         });
 
-        let choices: Vec<Choice> = choices_map
+        let choices = choices_map
             .iter()
             .map(|(i, choices)| {
                 let index = *i;
@@ -123,6 +382,9 @@ impl<'a> DeltaReceiver<'a> {
                 let mut function_call = false;
                 let mut function_call_name: Option<String> = None;
                 let mut arguments: Option<String> = None;
+                let mut tool_calls = ToolCallAccumulator::new();
+                let mut refusal: Option<String> = None;
+                let mut audio: Option<MessageAudio> = None;
 
                 choices.iter().for_each(|choice| {
                     if let Some(reason) = &choice.finish_reason {
@@ -155,27 +417,49 @@ impl<'a> DeltaReceiver<'a> {
                             }
                         }
                     }
+
+                    if let Some(r) = &choice.delta.refusal {
+                        refusal.get_or_insert_with(String::new).push_str(r);
+                    }
+
+                    for call in choice.delta.tool_calls.iter().flatten() {
+                        tool_calls.push(call);
+                    }
+
+                    if let Some(a) = &choice.delta.audio {
+                        audio.get_or_insert_with(MessageAudio::default).push(a);
+                    }
                 });
 
-                Choice {
+                // a cancelled or timed out stream can end before these arrived
+                let missing = |what| InternalError::IncompleteChoice(index, what);
+                let function_call = match function_call {
+                    true => Some(FunctionCall {
+                        name: function_call_name.ok_or_else(|| missing("function name"))?,
+                        arguments: arguments.ok_or_else(|| missing("function arguments"))?,
+                    }),
+                    false => None,
+                };
+
+                Ok(Choice {
                     index,
                     message: Message {
-                        // role should always be there, panic otherwise make this return an error later
-                        role: role.unwrap(),
-                        content,
+                        role: role.ok_or_else(|| missing("role"))?,
+                        content: content.map(Content::Text),
                         name: None,
-                        function_call: match function_call {
-                            true => Some(FunctionCall {
-                                name: function_call_name.unwrap(),
-                                arguments: arguments.unwrap(),
-                            }),
-                            false => None,
+                        function_call,
+                        tool_calls: match tool_calls.is_empty() {
+                            true => None,
+                            false => Some(tool_calls.into_calls()),
                         },
+                        tool_call_id: None,
+                        refusal,
+                        audio,
                     },
                     finish_reason,
-                }
+                })
             })
-            .collect();
+            .collect::<Result<Vec<Choice>, InternalError>>()?;
 
         let usage = Usage {
             prompt_tokens: self.usage as u64,
@@ -200,27 +484,286 @@ impl<'a> DeltaReceiver<'a> {
     }
 }
 
-pub async fn forward_stream(
-    mut es: EventSource,
-    tx: Sender<UtilsResult<ChatDelta>>,
-) -> anyhow::Result<()> {
-    // Process each event from the EventSource
-    while let Some(event) = es.next().await {
-        // Handle errors in the event
-        let event = event?;
-
-        // Process Message events
-        if let Event::Message(message) = event {
-            // Break the loop if the message data is "[DONE]"
-            if message.data == "[DONE]" {
-                break;
+impl Stream for DeltaReceiver {
+    type Item = UtilsResult<ChatDelta>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.stream.poll_next_unpin(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(delta))) => self.deltas.push(delta.clone()),
+            // drop the finished (or cancelled) stream right away so the connection gets closed
+            Poll::Ready(None) => self.stream = stream::empty().boxed(),
+            _ => {}
+        }
+        poll
+    }
+}
+
+// Turns the event source into a stream of deltas. The stream ends on `[DONE]` or right after
+// yielding the first error, a connection that closes before the response finished is an error too.
+pub fn delta_stream(
+    es: EventSource,
+    timeouts: StreamTimeouts,
+) -> impl Stream<Item = UtilsResult<ChatDelta>> + Send {
+    stream::unfold(Some((es, false, false)), move |state| async move {
+        let (mut es, mut received, mut finished) = state?;
+        loop {
+            let (timeout, kind) = match received {
+                false => (timeouts.first_byte, TimeoutKind::FirstByte),
+                true => (timeouts.idle, TimeoutKind::Idle),
+            };
+            let event = match timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, es.next()).await {
+                    Ok(event) => event,
+                    Err(_) => {
+                        es.close();
+                        return Some((Err(InternalError::Timeout(kind).into()), None));
+                    }
+                },
+                None => es.next().await,
+            };
+
+            match event {
+                Some(Ok(Event::Open)) => continue,
+                Some(Ok(Event::Message(message))) => {
+                    received = true;
+                    if message.data == "[DONE]" {
+                        es.close();
+                        return None;
+                    }
+
+                    return match serialize::<ChatDelta>(&message.data) {
+                        Ok(delta) => {
+                            finished |= delta.choices.iter().any(|c| c.finish_reason.is_some());
+                            Some((Ok(delta), Some((es, received, finished))))
+                        }
+                        Err(e) => {
+                            es.close();
+                            Some((Err(e), None))
+                        }
+                    };
+                }
+                // some openai compatible servers just close the connection instead of sending `[DONE]`
+                Some(Err(reqwest_eventsource::Error::StreamEnded)) if finished => return None,
+                Some(Err(e)) => {
+                    es.close();
+                    return Some((Err(error_from_event_source(e).await), None));
+                }
+                None if finished => return None,
+                None => return Some((Err(InternalError::StreamClosed.into()), None)),
             }
+        }
+    })
+}
 
-            // Serialize the message data and send it
-            let chat = serialize(&message.data);
-            tx.send(chat).await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Reply, TestServer};
+    use crate::FunctionCallDelta;
+    use serde_json::{json, Value};
+
+    fn delta(
+        index: Option<usize>,
+        id: Option<&str>,
+        name: Option<&str>,
+        arguments: Option<&str>,
+    ) -> ToolCallDelta {
+        ToolCallDelta {
+            index,
+            id: id.map(str::to_string),
+            tool_type: id.map(|_| "function".to_string()),
+            function: Some(FunctionCallDelta {
+                name: name.map(str::to_string),
+                arguments: arguments.map(str::to_string),
+            }),
         }
     }
 
-    Ok(())
+    fn accumulate(deltas: &[ToolCallDelta]) -> Vec<(String, String, String)> {
+        let mut accumulator = ToolCallAccumulator::new();
+        for delta in deltas {
+            accumulator.push(delta);
+        }
+        accumulator
+            .into_calls()
+            .into_iter()
+            .map(|call| (call.id, call.function.name, call.function.arguments))
+            .collect()
+    }
+
+    fn call(id: &str, name: &str, arguments: &str) -> (String, String, String) {
+        (id.to_string(), name.to_string(), arguments.to_string())
+    }
+
+    #[test]
+    fn joins_arguments_split_across_deltas() {
+        let calls = accumulate(&[
+            delta(Some(0), Some("call_1"), Some("weather"), Some("")),
+            delta(Some(0), None, None, Some("{\"city\":")),
+            delta(Some(0), None, None, Some("\"Paris\"}")),
+        ]);
+        assert_eq!(calls, vec![call("call_1", "weather", "{\"city\":\"Paris\"}")]);
+    }
+
+    #[test]
+    fn separates_interleaved_indices() {
+        let calls = accumulate(&[
+            delta(Some(0), Some("call_1"), Some("weather"), None),
+            delta(Some(1), Some("call_2"), Some("time"), None),
+            delta(Some(0), None, None, Some("{\"city\":")),
+            delta(Some(1), None, None, Some("{\"zone\":\"CET\"}")),
+            delta(Some(0), None, None, Some("\"Paris\"}")),
+        ]);
+        assert_eq!(
+            calls,
+            vec![
+                call("call_1", "weather", "{\"city\":\"Paris\"}"),
+                call("call_2", "time", "{\"zone\":\"CET\"}"),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_id_and_name_arriving_after_arguments() {
+        let calls = accumulate(&[
+            delta(Some(0), None, None, Some("{\"a\":")),
+            delta(Some(0), Some("call_1"), Some("add"), Some("1}")),
+        ]);
+        assert_eq!(calls, vec![call("call_1", "add", "{\"a\":1}")]);
+    }
+
+    #[test]
+    fn new_id_on_a_reused_index_starts_a_new_call() {
+        let calls = accumulate(&[
+            delta(Some(0), Some("call_1"), Some("a"), Some("{}")),
+            delta(Some(0), Some("call_2"), Some("b"), Some("{}")),
+        ]);
+        assert_eq!(calls, vec![call("call_1", "a", "{}"), call("call_2", "b", "{}")]);
+    }
+
+    #[test]
+    fn calls_without_index_are_split_by_id() {
+        let calls = accumulate(&[
+            delta(None, Some("call_1"), Some("a"), Some("{\"x\"")),
+            delta(None, None, None, Some(":1}")),
+            delta(None, Some("call_2"), Some("b"), Some("{}")),
+        ]);
+        assert_eq!(calls, vec![call("call_1", "a", "{\"x\":1}"), call("call_2", "b", "{}")]);
+    }
+
+    #[test]
+    fn receiver_outlives_the_agent() {
+        fn assert_forwardable<T: Stream + Send + 'static>(_: &T) {}
+
+        let receiver = {
+            let agent = AiAgent::new("gpt-4o");
+            DeltaReceiver::from(stream::empty(), agent, 0)
+        };
+        assert_forwardable(&receiver);
+    }
+
+    fn content_delta(role: Option<&str>, content: &str) -> Value {
+        json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{"index": 0, "delta": {"role": role, "content": content}, "finish_reason": null}],
+        })
+    }
+
+    #[tokio::test]
+    async fn cancel_closes_the_connection_and_keeps_received_deltas() {
+        let server = TestServer::start(vec![Reply::events(&[
+            content_delta(Some("assistant"), "Hello"),
+            content_delta(None, " there"),
+        ])
+        .hang()])
+        .await;
+
+        let agent = AiAgent::new("gpt-4o");
+        let mut receiver = agent.create_stream_with(&server.client()).await.unwrap();
+        assert_eq!(receiver.receive_content(0).await.unwrap().as_deref(), Some("Hello"));
+        assert_eq!(receiver.receive_content(0).await.unwrap().as_deref(), Some(" there"));
+
+        // cancelled from elsewhere while nobody polls the receiver
+        let handle = receiver.cancel_handle();
+        tokio::spawn(async move { handle.cancel() }).await.unwrap();
+        assert!(server.closed(Duration::from_secs(5)).await);
+
+        assert!(receiver.next().await.is_none());
+        let chat = receiver.construct_chat().await.unwrap();
+        assert_eq!(chat.choices[0].message.content.as_ref().unwrap().text(), "Hello there");
+    }
+
+    #[tokio::test]
+    async fn dropping_the_receiver_closes_the_connection() {
+        let server = TestServer::start(vec![Reply::events(&[content_delta(Some("assistant"), "Hi")]).hang()]).await;
+
+        let agent = AiAgent::new("gpt-4o");
+        let mut receiver = agent.create_stream_with(&server.client()).await.unwrap();
+        assert!(receiver.next().await.unwrap().is_ok());
+        drop(receiver);
+
+        assert!(server.closed(Duration::from_secs(5)).await);
+    }
+
+    async fn construct(deltas: Vec<Value>) -> anyhow::Result<Chat> {
+        let deltas: Vec<UtilsResult<ChatDelta>> = deltas
+            .into_iter()
+            .map(|delta| Ok(serde_json::from_value(delta).unwrap()))
+            .collect();
+        DeltaReceiver::from(stream::iter(deltas), AiAgent::new("gpt-4o"), 0)
+            .construct_chat()
+            .await
+    }
+
+    fn function_delta(role: Option<&str>, name: Option<&str>, arguments: Option<&str>) -> Value {
+        json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{
+                "index": 0,
+                "delta": {"role": role, "function_call": {"name": name, "arguments": arguments}},
+                "finish_reason": null,
+            }],
+        })
+    }
+
+    #[tokio::test]
+    async fn missing_role_is_an_error() {
+        let e = construct(vec![content_delta(None, "Hello")]).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<InternalError>(),
+            Some(InternalError::IncompleteChoice(0, "role"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn function_call_without_name_is_an_error() {
+        let e = construct(vec![function_delta(Some("assistant"), None, Some("{\"a\":"))])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<InternalError>(),
+            Some(InternalError::IncompleteChoice(0, "function name"))
+        ));
+    }
+
+    #[tokio::test]
+    async fn constructs_function_calls() {
+        let chat = construct(vec![
+            function_delta(Some("assistant"), Some("add"), Some("")),
+            function_delta(None, None, Some("{\"a\":1}")),
+        ])
+        .await
+        .unwrap();
+
+        let call = chat.choices[0].message.function_call.as_ref().unwrap();
+        assert_eq!(call.name, "add");
+        assert_eq!(call.arguments, "{\"a\":1}");
+    }
 }
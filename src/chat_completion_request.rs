@@ -1,17 +1,16 @@
-use crate::chat_completion_delta::forward_stream;
-use crate::error::{InternalError, OpenAIError};
-use crate::error::UtilsResult;
+use crate::chat_completion_delta::StreamTimeouts;
+use crate::error::{InternalError, OpenAIError, ResponseInfo, UnexpectedResponse};
+use crate::error::{Error, UtilsResult};
 use crate::{calculate_message_tokens, DeltaReceiver};
-use crate::{Chat, OPENAI_API_KEY};
-use crate::{Function, Message};
-use log::{error, trace};
-use reqwest::Method;
-use reqwest_eventsource::RequestBuilderExt;
+use crate::{AudioConfig, Chat, Client, Content, MessageAudio, RetryPolicy, DEFAULT_CLIENT};
+use crate::{Function, FunctionCall, FunctionRegistry, Message, ResponseFormat, Tool, ToolCall, ToolChoice, ToolFunction};
 use schemars::JsonSchema;
-use serde::Deserialize;
+use reqwest::Response;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use futures_util::future::join_all;
+use std::future::Future;
 use std::{collections::HashMap, vec};
-use serde_json::to_string_pretty;
-use tokio::sync::mpsc;
 
 #[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
 pub struct ChatCompletionRequest {
@@ -24,6 +23,25 @@ pub struct ChatCompletionRequest {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub function_call: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallel_tool_calls: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+
+    // e.g. `["text", "audio"]` to get a spoken answer as well
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modalities: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioConfig>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f64>,
 
@@ -62,6 +80,12 @@ impl ChatCompletionRequest {
             messages: vec![],
             functions: None,
             function_call: None,
+            tools: None,
+            tool_choice: None,
+            parallel_tool_calls: None,
+            response_format: None,
+            modalities: None,
+            audio: None,
             temperature: None,
             top_p: None,
             n: None,
@@ -74,6 +98,25 @@ impl ChatCompletionRequest {
             user: None,
         }
     }
+
+    // approximation of the prompt tokens, including the per message overhead
+    pub fn prompt_tokens(&self) -> usize {
+        self.messages
+            .iter()
+            .fold(3, |acc, m| acc + calculate_message_tokens(m) + 4)
+    }
+
+    // worst case token cost of the request, this is what rate limits are counted against
+    pub fn estimated_tokens(&self) -> u64 {
+        self.prompt_tokens() as u64 + self.max_tokens.unwrap_or(0) * self.n.unwrap_or(1)
+    }
+
+    // catches definitions the api would reject before anything is sent
+    pub fn validate(&self) -> UtilsResult<()> {
+        let functions = self.functions.iter().flatten();
+        let tools = self.tools.iter().flatten().map(|tool| &tool.function);
+        functions.chain(tools).try_for_each(Function::validate)
+    }
 }
 
 #[derive(Debug, Clone, serde_derive::Serialize, serde_derive::Deserialize)]
@@ -90,6 +133,25 @@ pub struct AiAgent {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub function_call: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Tool>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallel_tool_calls: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+
+    // e.g. `["text", "audio"]` to get a spoken answer as well
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modalities: Option<Vec<String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioConfig>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub temperature: Option<f64>,
 
@@ -116,12 +178,24 @@ pub struct AiAgent {
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
+
+    // overrides the retry policy of the client the agent is executed against
+    #[serde(skip)]
+    pub retry_policy: Option<RetryPolicy>,
+
+    // overrides the stream timeouts of the client the agent is executed against
+    #[serde(skip)]
+    pub stream_timeouts: Option<StreamTimeouts>,
+
+    // callables for the functions the model may call, see `register_function`
+    #[serde(skip)]
+    pub registry: FunctionRegistry,
 }
 
 impl AiAgent {
     // request part
     pub fn build_request(&self, stream: bool) -> ChatCompletionRequest {
-        let messages = if let Some(system_message) = &self.system_message {
+        let mut messages = if let Some(system_message) = &self.system_message {
             let mut messages = self.messages.clone();
             messages.insert(0, system_message.clone());
             messages
@@ -129,11 +203,22 @@ impl AiAgent {
             self.messages.clone()
         };
 
+        // previous spoken answers are referred to by id, sending the audio again is rejected
+        for message in &mut messages {
+            message.audio = message.audio.as_ref().map(MessageAudio::reference);
+        }
+
         ChatCompletionRequest {
             model: self.model.clone(),
             messages,
             functions: self.functions.clone(),
             function_call: self.function_call.clone(),
+            tools: self.tools.clone(),
+            tool_choice: self.tool_choice.clone(),
+            parallel_tool_calls: self.parallel_tool_calls,
+            response_format: self.response_format.clone(),
+            modalities: self.modalities.clone(),
+            audio: self.audio.clone(),
             temperature: self.temperature,
             top_p: self.top_p,
             n: self.n,
@@ -147,50 +232,67 @@ impl AiAgent {
         }
     }
 
+    fn retry_policy<'a>(&'a self, client: &'a Client) -> &'a RetryPolicy {
+        self.retry_policy.as_ref().unwrap_or(client.retry_policy())
+    }
+
+    fn stream_timeouts(&self, client: &Client) -> StreamTimeouts {
+        self.stream_timeouts.unwrap_or(*client.stream_timeouts())
+    }
+
     pub async fn create(&self) -> UtilsResult<Chat> {
-        let api_key = OPENAI_API_KEY.read().expect("failed to get lock").clone().ok_or_else(|| InternalError::ConfigurationError("API key not set".to_string()))?;
-        
-        trace!("request body: {}", to_string_pretty(&self.build_request(false)).unwrap());
-        let req = reqwest::Client::new()
-            .post("https://api.openai.com/v1/chat/completions")
-            .json(&self.build_request(false))
-            .bearer_auth(api_key)
-            .header("Content-Type", "application/json")
-            .send()
-            .await.map_err(|e| InternalError::RequestBuildError(e))?;
+        self.create_with(&DEFAULT_CLIENT).await
+    }
 
-        let res = req.text().await.map_err(|e| InternalError::RequestBuildError(e))?;
-        serialize(&res)
+    pub async fn create_with(&self, client: &Client) -> UtilsResult<Chat> {
+        self.execute(client, &self.build_request(false)).await
     }
 
-    pub async fn create_stream(&self) -> UtilsResult<DeltaReceiver> {
-        let api_key = OPENAI_API_KEY.read()
-            .expect("failed to get lock")
-            .as_ref()
-            .ok_or_else(|| InternalError::ConfigurationError("API key not set".to_string()))?
-            .to_string();
-
-        let (tx, rx) = mpsc::channel(64);
-        trace!("request body: {}", to_string_pretty(&self.build_request(true)).unwrap());
-        let es = reqwest::Client::new()
-            .request(Method::POST, "https://api.openai.com/v1/chat/completions")
-            .json(&self.build_request(true))
-            .bearer_auth(api_key)
-            .header("Content-Type", "application/json")
-            .eventsource()
-            .expect("cannot create eventsource? shouldn't happen i think.");
-
-        tokio::spawn(async move {
-            if let Err(e) = forward_stream(es, tx).await {
-                error!("Error in forward_stream: {}", e);
-            }
-        });
+    async fn execute(&self, client: &Client, request: &ChatCompletionRequest) -> UtilsResult<Chat> {
+        let res = client
+            .send(client.chat_request(request)?, self.retry_policy(client), || request.estimated_tokens())
+            .await?;
 
-        let usage = self.build_request(true).messages.iter().fold(3, |acc, m| {
-            acc + calculate_message_tokens(m) + 4
-        });
+        serialize_response(res).await
+    }
 
-        Ok(DeltaReceiver::from(rx, self, usage))
+    pub async fn create_typed<T: JsonSchema + DeserializeOwned>(&self) -> UtilsResult<(T, Chat)> {
+        self.create_typed_with(&DEFAULT_CLIENT).await
+    }
+
+    // asks for a response matching the schema of `T` and deserializes the first choice into it
+    pub async fn create_typed_with<T: JsonSchema + DeserializeOwned>(&self, client: &Client) -> UtilsResult<(T, Chat)> {
+        let mut request = self.build_request(false);
+        request.response_format = Some(ResponseFormat::strict_json_schema::<T>()?);
+
+        let chat = self.execute(client, &request).await?;
+        let message = &chat.choices.first().ok_or(InternalError::NoChoices)?.message;
+        if let Some(refusal) = &message.refusal {
+            Err(InternalError::Refusal(refusal.clone()))?
+        }
+
+        let content = message.content.as_ref().map(Content::text).unwrap_or_default();
+        let value = serde_json::from_str(&content).map_err(InternalError::SerializationError)?;
+        Ok((value, chat))
+    }
+
+    pub async fn create_stream(&self) -> UtilsResult<DeltaReceiver> {
+        self.create_stream_with(&DEFAULT_CLIENT).await
+    }
+
+    pub async fn create_stream_with(&self, client: &Client) -> UtilsResult<DeltaReceiver> {
+        let request = self.build_request(true);
+        let timeouts = self.stream_timeouts(client);
+        let es = client
+            .open_stream(
+                client.chat_request(&request)?,
+                self.retry_policy(client),
+                || request.estimated_tokens(),
+                timeouts.connect,
+            )
+            .await?;
+
+        Ok(DeltaReceiver::spawn(es, timeouts, self.clone(), request.prompt_tokens()))
     }
 
 
@@ -203,6 +305,12 @@ impl AiAgent {
             messages: vec![],
             functions: None,
             function_call: None,
+            tools: None,
+            tool_choice: None,
+            parallel_tool_calls: None,
+            response_format: None,
+            modalities: None,
+            audio: None,
             temperature: None,
             top_p: None,
             n: None,
@@ -212,11 +320,14 @@ impl AiAgent {
             frequency_penalty: None,
             logit_bias: None,
             user: None,
+            retry_policy: None,
+            stream_timeouts: None,
+            registry: FunctionRegistry::new(),
         }
     }
 
     pub fn with_system_message<'a>(mut self, system_message: impl Into<&'a str>) -> Self {
-        self.system_message = Some(Message::new("system").with_content(system_message.into()));
+        self.system_message = Some(Message::new("system").with_content(system_message.into().to_string()));
         self
     }
 
@@ -230,6 +341,38 @@ impl AiAgent {
         self
     }
 
+    pub fn with_tools(mut self, tools: Vec<Tool>) -> Self {
+        self.tools = Some(tools);
+        self
+    }
+
+    pub fn with_tool_choice(mut self, tool_choice: ToolChoice) -> Self {
+        self.tool_choice = Some(tool_choice);
+        self
+    }
+
+    pub fn with_parallel_tool_calls(mut self, parallel_tool_calls: bool) -> Self {
+        self.parallel_tool_calls = Some(parallel_tool_calls);
+        self
+    }
+
+    pub fn with_response_format(mut self, response_format: ResponseFormat) -> Self {
+        self.response_format = Some(response_format);
+        self
+    }
+
+    pub fn with_modalities(mut self, modalities: Vec<String>) -> Self {
+        self.modalities = Some(modalities);
+        self
+    }
+
+    // asks for text and audio answers
+    pub fn with_audio(mut self, audio: AudioConfig) -> Self {
+        self.modalities = Some(vec!["text".to_string(), "audio".to_string()]);
+        self.audio = Some(audio);
+        self
+    }
+
     pub fn with_temperature(mut self, temperature: f64) -> Self {
         self.temperature = Some(temperature);
         self
@@ -275,6 +418,16 @@ impl AiAgent {
         self
     }
 
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    pub fn with_stream_timeouts(mut self, stream_timeouts: StreamTimeouts) -> Self {
+        self.stream_timeouts = Some(stream_timeouts);
+        self
+    }
+
     // mutably update part
 
     pub fn push_message(&mut self, message: Message) {
@@ -293,6 +446,119 @@ impl AiAgent {
         }
     }
 
+    pub fn push_tool(&mut self, tool: Tool) {
+        self.tools.get_or_insert_with(Vec::new).push(tool);
+    }
+
+    pub fn push_function_tool<FunctionArgs, Func, T>(&mut self, function: &Func, function_name: &str)
+    where
+        FunctionArgs: JsonSchema,
+        Func: FnMut(FunctionArgs) -> T,
+    {
+        self.push_tool(Tool::from(function, function_name));
+    }
+
+    // registers the function for dispatch and offers it to the model as a tool
+    pub fn register_function<FunctionArgs, Func, T>(&mut self, function: Func, function_name: &str)
+    where
+        FunctionArgs: JsonSchema + DeserializeOwned + Send + 'static,
+        Func: Fn(FunctionArgs) -> T + Send + Sync + 'static,
+        T: Serialize,
+    {
+        let definition = self.registry.register(function, function_name);
+        self.replace_tool(definition);
+    }
+
+    pub fn register_async_function<FunctionArgs, Func, Fut, T>(&mut self, function: Func, function_name: &str)
+    where
+        FunctionArgs: JsonSchema + DeserializeOwned + Send + 'static,
+        Func: Fn(FunctionArgs) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = T> + Send + 'static,
+        T: Serialize,
+    {
+        let definition = self.registry.register_async(function, function_name);
+        self.replace_tool(definition);
+    }
+
+    // registers a tool generated with `#[openai_tool]`
+    pub fn register_tool<T: ToolFunction>(&mut self) {
+        let definition = T::register(&mut self.registry);
+        self.replace_tool(definition);
+    }
+
+    fn replace_tool(&mut self, definition: Function) {
+        let tools = self.tools.get_or_insert_with(Vec::new);
+        tools.retain(|tool| tool.function.name != definition.name);
+        tools.push(Tool::function(definition));
+    }
+
+    // runs a legacy function call and appends the result as a `function` message
+    pub async fn call_function(&mut self, call: &FunctionCall) -> UtilsResult<Message> {
+        let content = self.registry.call(call).await?;
+        let message = Message::new("function")
+            .with_name(&call.name)
+            .with_content(content);
+
+        self.messages.push(message.clone());
+        Ok(message)
+    }
+
+    // runs a tool call and appends the result as a `tool` message
+    pub async fn call_tool(&mut self, call: &ToolCall) -> UtilsResult<Message> {
+        let content = self.registry.call(&call.function).await?;
+        let message = Message::tool(&call.id, content);
+
+        self.messages.push(message.clone());
+        Ok(message)
+    }
+
+    // runs every call requested by an assistant message, parallel tool calls concurrently,
+    // and appends the results in the order they were requested. a failed call still gets a
+    // result describing the error, since the api rejects tool calls left without an answer,
+    // and the first failure is returned once all results are appended.
+    pub async fn dispatch(&mut self, message: &Message) -> UtilsResult<Vec<Message>> {
+        let (results, error) = self.execute_calls(message).await;
+        self.messages.extend(results.iter().cloned());
+        match error {
+            Some(e) => Err(e),
+            None => Ok(results),
+        }
+    }
+
+    // the result message of every call, failures are described to the model in their result.
+    // also returns the first failure.
+    pub(crate) async fn execute_calls(&self, message: &Message) -> (Vec<Message>, Option<Error>) {
+        let mut results = Vec::new();
+        let mut error = None;
+        let mut content = |result: UtilsResult<String>| match result {
+            Ok(content) => content,
+            Err(e) => {
+                let content = match e.argument_errors() {
+                    Some(errors) => errors.correction_message(),
+                    None => format!("error: {}", e),
+                };
+                error.get_or_insert(e);
+                content
+            }
+        };
+
+        if let Some(call) = &message.function_call {
+            results.push(
+                Message::new("function")
+                    .with_name(&call.name)
+                    .with_content(content(self.registry.call(call).await)),
+            );
+        }
+
+        let tool_calls = message.tool_calls.iter().flatten();
+        let contents = join_all(tool_calls.clone().map(|call| self.registry.call(&call.function))).await;
+        for (call, result) in tool_calls.zip(contents) {
+            results.push(Message::tool(&call.id, content(result)));
+        }
+
+        (results, error)
+    }
+
     pub fn push_stop(&mut self, stop: impl Into<String>) {
         if let Some(stops) = &mut self.stop {
             stops.push(stop.into());
@@ -313,17 +579,159 @@ impl AiAgent {
 }
 
 pub fn serialize<'a, T: Deserialize<'a>>(res: &'a str) -> UtilsResult<T> {
+    serialize_with_info(res, ResponseInfo::default())
+}
+
+pub async fn serialize_response<T: DeserializeOwned>(res: Response) -> UtilsResult<T> {
+    let info = ResponseInfo::new(res.status(), res.headers());
+    let body = res.text().await.map_err(InternalError::RequestBuildError)?;
+    serialize_with_info(&body, info)
+}
+
+// turns a failed response into an error, whether or not the body is an openai error envelope
+pub(crate) async fn error_from_response(res: Response) -> Error {
+    let info = ResponseInfo::new(res.status(), res.headers());
+    match res.text().await {
+        Ok(body) => match serde_json::from_str::<ErrorWrapper>(&body) {
+            Ok(err) => err.error.with_response_info(&info).into(),
+            Err(source) => unexpected_response(info, &body, source),
+        },
+        Err(e) => InternalError::RequestBuildError(e).into(),
+    }
+}
+
+// gives event source failures the same shape as errors from blocking requests
+pub(crate) async fn error_from_event_source(e: reqwest_eventsource::Error) -> Error {
+    match e {
+        reqwest_eventsource::Error::InvalidStatusCode(_, res)
+        | reqwest_eventsource::Error::InvalidContentType(_, res) => error_from_response(res).await,
+        reqwest_eventsource::Error::Transport(e) => InternalError::RequestBuildError(e).into(),
+        reqwest_eventsource::Error::StreamEnded => InternalError::StreamClosed.into(),
+        e => InternalError::from(e).into(),
+    }
+}
+
+fn serialize_with_info<'a, T: Deserialize<'a>>(res: &'a str, info: ResponseInfo) -> UtilsResult<T> {
     match serde_json::from_str::<T>(res) {
         Ok(chat) => Ok(chat),
-        Err(_) => {
-            #[derive(Deserialize)]
-            struct TempWrapper {
-                error: OpenAIError
-            }
+        Err(source) => match serde_json::from_str::<ErrorWrapper>(res) {
+            Ok(err) => Err(err.error.with_response_info(&info).into()),
+            Err(_) => Err(unexpected_response(info, res, source)),
+        },
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorWrapper {
+    error: OpenAIError,
+}
+
+fn unexpected_response(info: ResponseInfo, body: &str, source: serde_json::Error) -> Error {
+    InternalError::UnexpectedResponse(Box::new(UnexpectedResponse {
+        info,
+        body: body.to_string(),
+        source,
+    }))
+    .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Reply, TestServer};
+
+    #[derive(serde_derive::Deserialize, JsonSchema)]
+    struct AddArgs {
+        a: i64,
+        b: i64,
+    }
 
-            let err =
-                serde_json::from_str::<TempWrapper>(res).unwrap_or_else(|_| panic!("{}", res));
-            Err(err.error.into())
+    fn tool_call(id: &str, name: &str, arguments: &str) -> ToolCall {
+        ToolCall {
+            id: id.to_string(),
+            tool_type: "function".to_string(),
+            function: FunctionCall {
+                name: name.to_string(),
+                arguments: arguments.to_string(),
+            },
         }
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn dispatch_answers_every_call_when_one_fails() {
+        let mut agent = AiAgent::new("gpt-4o");
+        agent.register_function(|args: AddArgs| args.a + args.b, "add");
+
+        let mut message = Message::new("assistant");
+        message.tool_calls = Some(vec![
+            tool_call("call_1", "add", r#"{"a":1,"b":2}"#),
+            tool_call("call_2", "missing", "{}"),
+            tool_call("call_3", "add", r#"{"a":3,"b":4}"#),
+        ]);
+        agent.push_message(message.clone());
+
+        let e = agent.dispatch(&message).await.unwrap_err();
+        assert!(matches!(e, Error::Internal(InternalError::UnknownFunction(_))));
+
+        let results: Vec<_> = agent.messages[1..]
+            .iter()
+            .map(|m| (m.tool_call_id.clone().unwrap(), m.content.as_ref().unwrap().text()))
+            .collect();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0], ("call_1".to_string(), "3".to_string()));
+        assert_eq!(results[1].0, "call_2");
+        assert!(results[1].1.starts_with("error: "));
+        assert_eq!(results[2], ("call_3".to_string(), "7".to_string()));
+    }
+
+    fn unexpected(e: Error) -> Box<UnexpectedResponse> {
+        match e {
+            Error::Internal(InternalError::UnexpectedResponse(e)) => e,
+            e => panic!("expected an unexpected response, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn truncated_body_keeps_the_raw_text() {
+        let body = r#"{"id": "chatcmpl-1", "object": "chat.comp"#;
+        let e = unexpected(serialize::<Chat>(body).unwrap_err());
+        assert_eq!(e.body, body);
+        assert_eq!(e.info.status, None);
+    }
+
+    #[test]
+    fn error_envelope_becomes_an_api_error() {
+        let body = r#"{"error": {"message": "bad model", "type": "invalid_request_error", "param": null, "code": "model_not_found"}}"#;
+        match serialize::<Chat>(body).unwrap_err() {
+            Error::OpenAI(e) => {
+                assert_eq!(e.message(), "bad model");
+                assert_eq!(e.code(), Some("model_not_found"));
+            }
+            e => panic!("expected an api error, got {:?}", e),
+        }
+    }
+
+    #[tokio::test]
+    async fn html_error_page_keeps_status_and_body() {
+        let page = "<html><body><h1>502 Bad Gateway</h1></body></html>";
+        let server = TestServer::start(vec![Reply::text(502, "text/html", page)]).await;
+
+        let e = AiAgent::new("gpt-4o").create_with(&server.client()).await.unwrap_err();
+        assert_eq!(e.status(), Some(502));
+        assert_eq!(e.request_id(), Some("req_test"));
+
+        let e = unexpected(e);
+        assert_eq!(e.body, page);
+        assert_eq!(e.info.status, Some(502));
+    }
+
+    #[tokio::test]
+    async fn truncated_success_body_keeps_status_and_body() {
+        let body = r#"{"id": "chatcmpl-1", "choices": [{"index": 0, "mess"#;
+        let server = TestServer::start(vec![Reply::text(200, "application/json", body)]).await;
+
+        let e = unexpected(AiAgent::new("gpt-4o").create_with(&server.client()).await.unwrap_err());
+        assert_eq!(e.body, body);
+        assert_eq!(e.info.status, Some(200));
+    }
+}
@@ -0,0 +1,582 @@
+use std::fmt;
+use std::time::Duration;
+
+use futures_util::StreamExt;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Method, RequestBuilder, Response};
+use reqwest_eventsource::retry::Never;
+use reqwest_eventsource::{Event, EventSource, RequestBuilderExt};
+
+use crate::chat_completion_delta::StreamTimeouts;
+use crate::chat_completion_request::{error_from_event_source, error_from_response};
+use crate::error::{Error, InternalError, TimeoutKind, UtilsResult};
+use crate::rate_limit::RateLimiter;
+use crate::retry::RetryPolicy;
+use crate::{ChatRequest, EmbeddingRequest, OPENAI_API_KEY};
+use log::{trace, warn};
+use serde::Serialize;
+use serde_json::to_string_pretty;
+
+const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1";
+const DEFAULT_CHAT_COMPLETIONS_PATH: &str = "chat/completions";
+const DEFAULT_EMBEDDINGS_PATH: &str = "embeddings";
+
+const DEFAULT_AZURE_API_VERSION: &str = "2024-06-01";
+
+const BASE_URL_ENV: &str = "OPENAI_BASE_URL";
+const CHAT_COMPLETIONS_PATH_ENV: &str = "OPENAI_CHAT_COMPLETIONS_PATH";
+
+// The api endpoints a client sends requests to. Each has its own configurable path and,
+// on azure, its own deployment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    ChatCompletions,
+    Embeddings,
+}
+
+#[derive(Debug, Clone)]
+pub struct AzureConfig {
+    pub api_version: String,
+
+    // deployments for chat completions and embeddings, when unset the model name of the
+    // request is used as the deployment name
+    pub deployment: Option<String>,
+    pub embeddings_deployment: Option<String>,
+}
+
+impl Default for AzureConfig {
+    fn default() -> Self {
+        Self {
+            api_version: DEFAULT_AZURE_API_VERSION.to_string(),
+            deployment: None,
+            embeddings_deployment: None,
+        }
+    }
+}
+
+// A handle to the api that agents are executed against. Cloning is cheap and
+// clones share the same connection pool.
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    api_key: Option<String>,
+    organization: Option<String>,
+    project: Option<String>,
+    base_url: String,
+    chat_completions_path: String,
+    embeddings_path: String,
+    headers: HeaderMap,
+    azure: Option<AzureConfig>,
+    retry_policy: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
+    stream_timeouts: StreamTimeouts,
+}
+
+impl Client {
+    // picks up `OPENAI_BASE_URL` and `OPENAI_CHAT_COMPLETIONS_PATH` from the environment if set
+    pub fn new() -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            api_key: None,
+            organization: None,
+            project: None,
+            base_url: std::env::var(BASE_URL_ENV).unwrap_or_else(|_| DEFAULT_BASE_URL.to_string()),
+            chat_completions_path: std::env::var(CHAT_COMPLETIONS_PATH_ENV)
+                .unwrap_or_else(|_| DEFAULT_CHAT_COMPLETIONS_PATH.to_string()),
+            embeddings_path: DEFAULT_EMBEDDINGS_PATH.to_string(),
+            headers: HeaderMap::new(),
+            azure: None,
+            retry_policy: RetryPolicy::default(),
+            rate_limiter: None,
+            stream_timeouts: StreamTimeouts::default(),
+        }
+    }
+
+    // `endpoint` is the resource endpoint, e.g. `https://my-resource.openai.azure.com`
+    pub fn azure(endpoint: impl Into<String>, api_version: impl Into<String>) -> Self {
+        Self {
+            base_url: endpoint.into(),
+            chat_completions_path: DEFAULT_CHAT_COMPLETIONS_PATH.to_string(),
+            azure: Some(AzureConfig {
+                api_version: api_version.into(),
+                ..Default::default()
+            }),
+            ..Self::new()
+        }
+    }
+
+    // builder part
+
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub fn with_organization(mut self, organization: impl Into<String>) -> Self {
+        self.organization = Some(organization.into());
+        self
+    }
+
+    pub fn with_project(mut self, project: impl Into<String>) -> Self {
+        self.project = Some(project.into());
+        self
+    }
+
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    pub fn with_chat_completions_path(mut self, path: impl Into<String>) -> Self {
+        self.chat_completions_path = path.into();
+        self
+    }
+
+    pub fn with_embeddings_path(mut self, path: impl Into<String>) -> Self {
+        self.embeddings_path = path.into();
+        self
+    }
+
+    // the azure settings only apply to clients created through `azure()`, azure mode is never
+    // turned on implicitly
+    pub fn with_azure_deployment(mut self, deployment: impl Into<String>) -> Self {
+        match &mut self.azure {
+            Some(azure) => azure.deployment = Some(deployment.into()),
+            None => {
+                warn!("ignoring azure deployment, the client was not created through Client::azure")
+            }
+        }
+        self
+    }
+
+    pub fn with_azure_embeddings_deployment(mut self, deployment: impl Into<String>) -> Self {
+        match &mut self.azure {
+            Some(azure) => azure.embeddings_deployment = Some(deployment.into()),
+            None => warn!(
+                "ignoring azure embeddings deployment, the client was not created through Client::azure"
+            ),
+        }
+        self
+    }
+
+    pub fn with_azure_api_version(mut self, api_version: impl Into<String>) -> Self {
+        match &mut self.azure {
+            Some(azure) => azure.api_version = api_version.into(),
+            None => warn!(
+                "ignoring azure api version, the client was not created through Client::azure"
+            ),
+        }
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    pub fn with_rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    pub fn with_stream_timeouts(mut self, stream_timeouts: StreamTimeouts) -> Self {
+        self.stream_timeouts = stream_timeouts;
+        self
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers.extend(headers);
+        self
+    }
+
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    // getters
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn chat_completions_path(&self) -> &str {
+        &self.chat_completions_path
+    }
+
+    pub fn embeddings_path(&self) -> &str {
+        &self.embeddings_path
+    }
+
+    pub fn organization(&self) -> Option<&str> {
+        self.organization.as_deref()
+    }
+
+    pub fn project(&self) -> Option<&str> {
+        self.project.as_deref()
+    }
+
+    pub fn http_client(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    pub fn azure_config(&self) -> Option<&AzureConfig> {
+        self.azure.as_ref()
+    }
+
+    pub fn is_azure(&self) -> bool {
+        self.azure.is_some()
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry_policy
+    }
+
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    pub fn stream_timeouts(&self) -> &StreamTimeouts {
+        &self.stream_timeouts
+    }
+
+    // request part
+
+    // `model` is only used on azure, where it doubles as the deployment name unless one is
+    // configured for the endpoint
+    pub fn url(&self, endpoint: Endpoint, model: &str) -> String {
+        let base_url = self.base_url.trim_end_matches('/');
+        let path = match endpoint {
+            Endpoint::ChatCompletions => &self.chat_completions_path,
+            Endpoint::Embeddings => &self.embeddings_path,
+        };
+
+        match &self.azure {
+            Some(azure) => {
+                let deployment = match endpoint {
+                    Endpoint::ChatCompletions => azure.deployment.as_deref(),
+                    Endpoint::Embeddings => azure.embeddings_deployment.as_deref(),
+                };
+
+                format!(
+                    "{}/openai/deployments/{}/{}?api-version={}",
+                    base_url,
+                    deployment.unwrap_or(model),
+                    path.trim_start_matches('/'),
+                    azure.api_version
+                )
+            }
+            None => format!("{}/{}", base_url, path.trim_start_matches('/')),
+        }
+    }
+
+    // falls back to the key set through `api_key()` when the client has none of its own.
+    // self hosted openai compatible servers usually don't need a key at all, so a missing
+    // key is only an error when talking to openai or azure.
+    pub(crate) fn resolve_api_key(&self) -> UtilsResult<Option<String>> {
+        if let Some(api_key) = &self.api_key {
+            return Ok(Some(api_key.clone()));
+        }
+
+        let global = OPENAI_API_KEY.read().expect("failed to get lock").clone();
+        let required = self.is_azure() || self.base_url.trim_end_matches('/') == DEFAULT_BASE_URL;
+        if global.is_none() && required {
+            Err(InternalError::ConfigurationError(
+                "API key not set".to_string(),
+            ))?
+        }
+
+        Ok(global)
+    }
+
+    pub(crate) fn request(
+        &self,
+        method: Method,
+        endpoint: Endpoint,
+        model: &str,
+    ) -> UtilsResult<RequestBuilder> {
+        let mut req = self
+            .http
+            .request(method, self.url(endpoint, model))
+            .headers(self.headers.clone());
+
+        if let Some(api_key) = self.resolve_api_key()? {
+            req = match self.azure {
+                Some(_) => req.header("api-key", api_key),
+                None => req.bearer_auth(api_key),
+            };
+        }
+
+        if let Some(organization) = &self.organization {
+            req = req.header("OpenAI-Organization", organization);
+        }
+
+        if let Some(project) = &self.project {
+            req = req.header("OpenAI-Project", project);
+        }
+
+        Ok(req)
+    }
+
+    pub(crate) fn chat_request(&self, request: &ChatRequest) -> UtilsResult<RequestBuilder> {
+        request.validate()?;
+        self.json_request(Endpoint::ChatCompletions, &request.model, request)
+    }
+
+    pub(crate) fn embeddings_request(
+        &self,
+        request: &EmbeddingRequest,
+    ) -> UtilsResult<RequestBuilder> {
+        self.json_request(Endpoint::Embeddings, &request.model, request)
+    }
+
+    fn json_request(
+        &self,
+        endpoint: Endpoint,
+        model: &str,
+        body: &impl Serialize,
+    ) -> UtilsResult<RequestBuilder> {
+        trace!("request body: {}", to_string_pretty(body).unwrap());
+
+        Ok(self
+            .request(Method::POST, endpoint, model)?
+            .json(body)
+            .header("Content-Type", "application/json"))
+    }
+
+    // the estimate is only computed when there is a rate limiter to feed it to
+    fn estimate_tokens(&self, tokens: impl FnOnce() -> u64) -> u64 {
+        match self.rate_limiter {
+            Some(_) => tokens(),
+            None => 0,
+        }
+    }
+
+    async fn acquire(&self, tokens: u64) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire(tokens).await;
+        }
+    }
+
+    fn update_rate_limits(&self, headers: &HeaderMap) {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.update_from_headers(headers);
+        }
+    }
+
+    // sends the request, retrying on rate limits, server errors and connection failures.
+    // `tokens` estimates the token cost of the request, used for client side rate limiting.
+    pub(crate) async fn send(
+        &self,
+        req: RequestBuilder,
+        policy: &RetryPolicy,
+        tokens: impl FnOnce() -> u64,
+    ) -> UtilsResult<Response> {
+        let tokens = self.estimate_tokens(tokens);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.acquire(tokens).await;
+            let res = try_clone(&req)?.send().await;
+            if let Ok(res) = &res {
+                self.update_rate_limits(res.headers());
+            }
+
+            let delay = match res {
+                Ok(res) if RetryPolicy::is_retryable_status(res.status()) => {
+                    // the body tells apart a rate limit from an exhausted quota, which won't go away by retrying
+                    let status = res.status();
+                    let headers = res.headers().clone();
+                    let e = error_from_response(res).await;
+                    if !(e.is_retryable() && policy.can_retry(attempt)) {
+                        return Err(e);
+                    }
+
+                    warn!(
+                        "request failed with status {}, retrying (attempt {}/{}): {}",
+                        status, attempt, policy.max_attempts, e
+                    );
+                    policy.delay(attempt, Some(&headers))
+                }
+                Ok(res) => return Ok(res),
+                Err(e) if RetryPolicy::is_retryable_transport(&e) && policy.can_retry(attempt) => {
+                    warn!(
+                        "request failed: {}, retrying (attempt {}/{})",
+                        e, attempt, policy.max_attempts
+                    );
+                    policy.backoff(attempt)
+                }
+                Err(e) => Err(InternalError::RequestBuildError(e))?,
+            };
+
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    // opens an event source and waits until the connection is established, retrying the same
+    // way as `send`. the event source's own reconnect logic is disabled since reconnecting
+    // would silently start a new completion.
+    pub(crate) async fn open_stream(
+        &self,
+        req: RequestBuilder,
+        policy: &RetryPolicy,
+        tokens: impl FnOnce() -> u64,
+        connect_timeout: Option<Duration>,
+    ) -> UtilsResult<EventSource> {
+        let tokens = self.estimate_tokens(tokens);
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.acquire(tokens).await;
+            let mut es = try_clone(&req)?
+                .eventsource()
+                .map_err(|e| InternalError::ConfigurationError(e.to_string()))?;
+            es.set_retry_policy(Box::new(Never));
+
+            let event = match connect_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, es.next()).await,
+                None => Ok(es.next().await),
+            };
+
+            // the failure of this attempt and how long to wait if it's worth retrying
+            let (e, delay): (Error, Option<Duration>) = match event {
+                Ok(Some(Ok(Event::Open))) => return Ok(es),
+                Err(_) => (
+                    InternalError::Timeout(TimeoutKind::Connect).into(),
+                    Some(policy.backoff(attempt)),
+                ),
+                Ok(Some(Ok(Event::Message(_)))) => (InternalError::StreamNotOpened.into(), None),
+                // the event source doesn't expose the headers of successful responses,
+                // so streams only feed the rate limiter on failures
+                Ok(Some(Err(reqwest_eventsource::Error::InvalidStatusCode(status, res)))) => {
+                    self.update_rate_limits(res.headers());
+                    let headers = res.headers().clone();
+                    let e = error_from_response(res).await;
+                    let retryable = RetryPolicy::is_retryable_status(status) && e.is_retryable();
+                    (e, retryable.then(|| policy.delay(attempt, Some(&headers))))
+                }
+                Ok(Some(Err(reqwest_eventsource::Error::Transport(e)))) => {
+                    let delay =
+                        RetryPolicy::is_retryable_transport(&e).then(|| policy.backoff(attempt));
+                    (InternalError::RequestBuildError(e).into(), delay)
+                }
+                Ok(Some(Err(e))) => (error_from_event_source(e).await, None),
+                Ok(None) => (InternalError::StreamClosed.into(), None),
+            };
+
+            es.close();
+            match delay {
+                Some(delay) if policy.can_retry(attempt) => {
+                    warn!(
+                        "stream failed: {}, retrying (attempt {}/{})",
+                        e, attempt, policy.max_attempts
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                _ => return Err(e),
+            }
+        }
+    }
+}
+
+fn try_clone(req: &RequestBuilder) -> UtilsResult<RequestBuilder> {
+    Ok(req
+        .try_clone()
+        .ok_or_else(|| InternalError::ConfigurationError("request cannot be cloned".to_string()))?)
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("api_key", &self.api_key.as_ref().map(|_| "<redacted>"))
+            .field("organization", &self.organization)
+            .field("project", &self.project)
+            .field("base_url", &self.base_url)
+            .field("chat_completions_path", &self.chat_completions_path)
+            .field("embeddings_path", &self.embeddings_path)
+            .field("headers", &self.headers)
+            .field("azure", &self.azure)
+            .field("retry_policy", &self.retry_policy)
+            .field("rate_limiter", &self.rate_limiter)
+            .field("stream_timeouts", &self.stream_timeouts)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn azure_routes_each_endpoint_to_its_deployment() {
+        let client = Client::azure("https://example.openai.azure.com/", "2024-06-01")
+            .with_azure_deployment("chat")
+            .with_azure_embeddings_deployment("embed");
+
+        assert_eq!(
+            client.url(Endpoint::ChatCompletions, "gpt-4o"),
+            "https://example.openai.azure.com/openai/deployments/chat/chat/completions?api-version=2024-06-01"
+        );
+        assert_eq!(
+            client.url(Endpoint::Embeddings, "text-embedding-3-small"),
+            "https://example.openai.azure.com/openai/deployments/embed/embeddings?api-version=2024-06-01"
+        );
+    }
+
+    #[test]
+    fn azure_embeddings_fall_back_to_the_model() {
+        let client = Client::azure("https://example.openai.azure.com", "2024-06-01")
+            .with_azure_deployment("chat");
+
+        assert_eq!(
+            client.url(Endpoint::Embeddings, "text-embedding-3-small"),
+            "https://example.openai.azure.com/openai/deployments/text-embedding-3-small/embeddings?api-version=2024-06-01"
+        );
+    }
+
+    #[test]
+    fn azure_settings_dont_enable_azure_mode() {
+        let client = Client::new()
+            .with_base_url("https://api.openai.com/v1")
+            .with_azure_deployment("chat")
+            .with_azure_api_version("2024-06-01");
+
+        assert!(!client.is_azure());
+        assert_eq!(
+            client.url(Endpoint::ChatCompletions, "gpt-4o"),
+            format!(
+                "https://api.openai.com/v1/{}",
+                client.chat_completions_path()
+            )
+        );
+    }
+
+    #[test]
+    fn azure_routes_by_endpoint_whatever_the_path() {
+        let client = Client::azure("https://example.openai.azure.com", "2024-06-01")
+            .with_chat_completions_path("/chat/completions")
+            .with_embeddings_path("/embeddings")
+            .with_azure_deployment("chat")
+            .with_azure_embeddings_deployment("embed");
+
+        assert_eq!(
+            client.url(Endpoint::ChatCompletions, "gpt-4o"),
+            "https://example.openai.azure.com/openai/deployments/chat/chat/completions?api-version=2024-06-01"
+        );
+        assert_eq!(
+            client.url(Endpoint::Embeddings, "text-embedding-3-small"),
+            "https://example.openai.azure.com/openai/deployments/embed/embeddings?api-version=2024-06-01"
+        );
+    }
+}
@@ -0,0 +1,350 @@
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::audio::InputAudio;
+use crate::error::{InternalError, UtilsResult};
+
+// The content of a message, plain text or a list of parts mixing text and images.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Content {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+impl Content {
+    // the text of the message, the text parts joined by newlines
+    pub fn text(&self) -> String {
+        match self {
+            Content::Text(text) => text.clone(),
+            Content::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Content::Text(text) => Some(text),
+            Content::Parts(_) => None,
+        }
+    }
+
+    pub fn parts(&self) -> Vec<ContentPart> {
+        match self {
+            Content::Text(text) => vec![ContentPart::text(text.clone())],
+            Content::Parts(parts) => parts.clone(),
+        }
+    }
+
+    // turns plain text into a text part first
+    pub fn push(&mut self, part: ContentPart) {
+        match self {
+            Content::Text(text) => {
+                *self = Content::Parts(vec![ContentPart::text(std::mem::take(text)), part]);
+            }
+            Content::Parts(parts) => parts.push(part),
+        }
+    }
+}
+
+impl From<String> for Content {
+    fn from(text: String) -> Self {
+        Content::Text(text)
+    }
+}
+
+impl From<&str> for Content {
+    fn from(text: &str) -> Self {
+        Content::Text(text.to_string())
+    }
+}
+
+impl From<Vec<ContentPart>> for Content {
+    fn from(parts: Vec<ContentPart>) -> Self {
+        Content::Parts(parts)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+    InputAudio { input_audio: InputAudio },
+}
+
+impl ContentPart {
+    pub fn text(text: impl Into<String>) -> Self {
+        ContentPart::Text { text: text.into() }
+    }
+
+    pub fn image(image_url: ImageUrl) -> Self {
+        ContentPart::ImageUrl { image_url }
+    }
+
+    pub fn audio(input_audio: InputAudio) -> Self {
+        ContentPart::InputAudio { input_audio }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageDetail {
+    Auto,
+    Low,
+    High,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ImageUrl {
+    // a link to the image or the image itself as a base64 data url
+    pub url: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<ImageDetail>,
+}
+
+impl ImageUrl {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            detail: None,
+        }
+    }
+
+    // png, jpeg, gif and webp are supported, the format is read from the data itself
+    pub fn from_bytes(bytes: &[u8]) -> UtilsResult<Self> {
+        let mime = image_format(bytes).ok_or_else(|| {
+            InternalError::ConfigurationError(
+                "unsupported image format, expected png, jpeg, gif or webp".to_string(),
+            )
+        })?;
+
+        Ok(Self::new(format!(
+            "data:{};base64,{}",
+            mime,
+            STANDARD.encode(bytes)
+        )))
+    }
+
+    pub fn from_file(path: impl AsRef<Path>) -> UtilsResult<Self> {
+        let bytes = std::fs::read(path).map_err(InternalError::IoError)?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn with_detail(mut self, detail: ImageDetail) -> Self {
+        self.detail = Some(detail);
+        self
+    }
+
+    // width and height, only known for images embedded as data urls
+    pub fn dimensions(&self) -> Option<(u32, u32)> {
+        let (header, data) = self.url.strip_prefix("data:")?.split_once(',')?;
+        if !header.ends_with(";base64") {
+            return None;
+        }
+
+        // the size is in the image header, so only the start of the data is decoded. jpegs can
+        // have metadata in front of it, then more is decoded until it's found.
+        let mut prefix: usize = 64;
+        loop {
+            // whole groups of 4 characters for 3 bytes, or everything
+            let end = match prefix.div_ceil(3) * 4 {
+                end if end < data.len() => end,
+                _ => data.len(),
+            };
+
+            let bytes = STANDARD.decode(&data[..end]).ok()?;
+            if let Some(dimensions) = image_dimensions(&bytes) {
+                return Some(dimensions);
+            }
+            if end == data.len() {
+                return None;
+            }
+            prefix *= 4;
+        }
+    }
+
+    // what the image costs in prompt tokens. images behind a link are estimated as 1024x1024
+    pub fn estimated_tokens(&self) -> usize {
+        if self.detail == Some(ImageDetail::Low) {
+            return 85;
+        }
+
+        let (width, height) = self.dimensions().unwrap_or((1024, 1024));
+        image_tokens(width, height)
+    }
+}
+
+// the cost of a high detail image: it's scaled to fit into 2048x2048, then down so that the
+// short side is at most 768 and every 512px tile costs 170 tokens on top of a base of 85
+pub fn image_tokens(width: u32, height: u32) -> usize {
+    let (mut width, mut height) = (width.max(1) as f64, height.max(1) as f64);
+
+    let fit = (2048.0 / width.max(height)).min(1.0);
+    width *= fit;
+    height *= fit;
+
+    let shrink = (768.0 / width.min(height)).min(1.0);
+    width *= shrink;
+    height *= shrink;
+
+    let tiles = (width / 512.0).ceil() * (height / 512.0).ceil();
+    170 * tiles as usize + 85
+}
+
+fn image_format(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        _ => None,
+    }
+}
+
+// reads the size from the image header without decoding the image
+fn image_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let u16_be =
+        |at: usize| Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?) as u32);
+    let u16_le =
+        |at: usize| Some(u16::from_le_bytes(bytes.get(at..at + 2)?.try_into().ok()?) as u32);
+    let u32_be = |at: usize| Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?));
+    let u24_le = |at: usize| {
+        let b = bytes.get(at..at + 3)?;
+        Some(b[0] as u32 | (b[1] as u32) << 8 | (b[2] as u32) << 16)
+    };
+
+    match image_format(bytes)? {
+        "image/png" => Some((u32_be(16)?, u32_be(20)?)),
+        "image/gif" => Some((u16_le(6)?, u16_le(8)?)),
+        "image/webp" => match bytes.get(12..16)? {
+            b"VP8 " => Some((u16_le(26)? & 0x3FFF, u16_le(28)? & 0x3FFF)),
+            b"VP8L" => {
+                let b = bytes.get(21..25)?;
+                let width = 1 + (b[0] as u32 | (b[1] as u32 & 0x3F) << 8);
+                let height =
+                    1 + (b[1] as u32 >> 6 | (b[2] as u32) << 2 | (b[3] as u32 & 0x0F) << 10);
+                Some((width, height))
+            }
+            b"VP8X" => Some((1 + u24_le(24)?, 1 + u24_le(27)?)),
+            _ => None,
+        },
+        "image/jpeg" => {
+            // walk the segments up to the start of frame, which holds the size
+            let mut at = 2;
+            loop {
+                if *bytes.get(at)? != 0xFF {
+                    return None;
+                }
+                let marker = *bytes.get(at + 1)?;
+                match marker {
+                    // padding
+                    0xFF => at += 1,
+                    0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                        return Some((u16_be(at + 7)?, u16_be(at + 5)?));
+                    }
+                    _ => at += 2 + u16_be(at + 2)? as usize,
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        bytes.extend(13u32.to_be_bytes());
+        bytes.extend(b"IHDR");
+        bytes.extend(width.to_be_bytes());
+        bytes.extend(height.to_be_bytes());
+        bytes.extend([8, 6, 0, 0, 0]);
+        // the rest of the image doesn't matter for the size
+        bytes.extend([0; 1000]);
+        bytes
+    }
+
+    // a jpeg with a large metadata segment before the frame header
+    fn jpeg(width: u16, height: u16, metadata: usize) -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        bytes.extend((metadata as u16 + 2).to_be_bytes());
+        bytes.extend(vec![0; metadata]);
+        bytes.extend([0xFF, 0xC0, 0x00, 0x11, 0x08]);
+        bytes.extend(height.to_be_bytes());
+        bytes.extend(width.to_be_bytes());
+        bytes.extend([0; 20]);
+        bytes
+    }
+
+    #[test]
+    fn reads_dimensions_from_data_urls() {
+        let image = ImageUrl::from_bytes(&png(640, 480)).unwrap();
+        assert!(image.url.starts_with("data:image/png;base64,"));
+        assert_eq!(image.dimensions(), Some((640, 480)));
+
+        let image = ImageUrl::from_bytes(&jpeg(1920, 1080, 30_000)).unwrap();
+        assert!(image.url.starts_with("data:image/jpeg;base64,"));
+        assert_eq!(image.dimensions(), Some((1920, 1080)));
+    }
+
+    #[test]
+    fn dimensions_are_unknown_for_links_and_broken_data() {
+        assert_eq!(
+            ImageUrl::new("https://example.com/cat.png").dimensions(),
+            None
+        );
+        assert_eq!(
+            ImageUrl::new("data:image/png;base64,AAAA").dimensions(),
+            None
+        );
+        assert_eq!(
+            ImageUrl::new("data:image/png;base64,!!!!").dimensions(),
+            None
+        );
+        assert_eq!(ImageUrl::new("data:text/plain,hello").dimensions(), None);
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        assert!(ImageUrl::from_bytes(b"BM not supported").is_err());
+        assert!(ImageUrl::from_bytes(&[]).is_err());
+    }
+
+    #[test]
+    fn high_detail_images_cost_tiles() {
+        // fits into a single tile
+        assert_eq!(image_tokens(512, 512), 255);
+        // scaled down to 768x768, 2x2 tiles
+        assert_eq!(image_tokens(1024, 1024), 765);
+        // fit into 2048, then the short side scaled to 768: 768x1536, 2x3 tiles
+        assert_eq!(image_tokens(2048, 4096), 1105);
+        // never scaled up
+        assert_eq!(image_tokens(100, 100), 255);
+        assert_eq!(image_tokens(0, 0), 255);
+    }
+
+    #[test]
+    fn estimates_tokens() {
+        let image = ImageUrl::from_bytes(&png(2048, 4096)).unwrap();
+        assert_eq!(image.estimated_tokens(), 1105);
+        assert_eq!(image.with_detail(ImageDetail::Low).estimated_tokens(), 85);
+
+        // unknown sizes are estimated as 1024x1024
+        let link = ImageUrl::new("https://example.com/cat.png");
+        assert_eq!(link.estimated_tokens(), 765);
+        assert_eq!(link.with_detail(ImageDetail::High).estimated_tokens(), 765);
+    }
+}
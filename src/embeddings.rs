@@ -0,0 +1,396 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::chat_completion_request::serialize_response;
+use crate::error::{InternalError, UtilsResult};
+use crate::{calculate_tokens, Client, RetryPolicy, Usage, DEFAULT_CLIENT};
+
+// per request limits of the embeddings endpoint
+const MAX_BATCH_SIZE: usize = 2048;
+const MAX_BATCH_TOKENS: usize = 300_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Text(String),
+    Texts(Vec<String>),
+    Tokens(Vec<u32>),
+    TokenArrays(Vec<Vec<u32>>),
+}
+
+impl EmbeddingInput {
+    pub fn len(&self) -> usize {
+        match self {
+            EmbeddingInput::Text(_) | EmbeddingInput::Tokens(_) => 1,
+            EmbeddingInput::Texts(texts) => texts.len(),
+            EmbeddingInput::TokenArrays(arrays) => arrays.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // splits the input into requests that stay under both limits, keeping the order
+    fn batches(self, max_size: usize, max_tokens: usize) -> Vec<EmbeddingInput> {
+        match self {
+            EmbeddingInput::Text(text) => vec![EmbeddingInput::Texts(vec![text])],
+            EmbeddingInput::Texts(texts) => {
+                batch(texts, |text| calculate_tokens(text), max_size, max_tokens)
+                    .into_iter()
+                    .map(EmbeddingInput::Texts)
+                    .collect()
+            }
+            EmbeddingInput::Tokens(tokens) => vec![EmbeddingInput::TokenArrays(vec![tokens])],
+            EmbeddingInput::TokenArrays(arrays) => batch(arrays, Vec::len, max_size, max_tokens)
+                .into_iter()
+                .map(EmbeddingInput::TokenArrays)
+                .collect(),
+        }
+    }
+}
+
+impl From<String> for EmbeddingInput {
+    fn from(text: String) -> Self {
+        EmbeddingInput::Text(text)
+    }
+}
+
+impl From<&str> for EmbeddingInput {
+    fn from(text: &str) -> Self {
+        EmbeddingInput::Text(text.to_string())
+    }
+}
+
+impl From<Vec<String>> for EmbeddingInput {
+    fn from(texts: Vec<String>) -> Self {
+        EmbeddingInput::Texts(texts)
+    }
+}
+
+impl From<Vec<&str>> for EmbeddingInput {
+    fn from(texts: Vec<&str>) -> Self {
+        EmbeddingInput::Texts(texts.into_iter().map(str::to_string).collect())
+    }
+}
+
+impl From<Vec<u32>> for EmbeddingInput {
+    fn from(tokens: Vec<u32>) -> Self {
+        EmbeddingInput::Tokens(tokens)
+    }
+}
+
+impl From<Vec<Vec<u32>>> for EmbeddingInput {
+    fn from(arrays: Vec<Vec<u32>>) -> Self {
+        EmbeddingInput::TokenArrays(arrays)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EncodingFormat {
+    Float,
+    // smaller responses, decoded back into floats transparently
+    Base64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingRequest {
+    pub model: String,
+    pub input: EmbeddingInput,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding_format: Option<EncodingFormat>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+}
+
+impl EmbeddingRequest {
+    pub fn estimated_tokens(&self) -> u64 {
+        let tokens = match &self.input {
+            EmbeddingInput::Text(text) => calculate_tokens(text),
+            EmbeddingInput::Texts(texts) => texts.iter().map(|text| calculate_tokens(text)).sum(),
+            EmbeddingInput::Tokens(tokens) => tokens.len(),
+            EmbeddingInput::TokenArrays(arrays) => arrays.iter().map(Vec::len).sum(),
+        };
+        tokens as u64
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Embedding {
+    // position of the input this embedding belongs to
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingResponse {
+    // in the order of the input
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: Usage,
+}
+
+impl EmbeddingResponse {
+    pub fn vectors(self) -> Vec<Vec<f32>> {
+        self.data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect()
+    }
+}
+
+// the response as sent, embeddings are either floats or base64 encoded little endian f32s
+#[derive(Deserialize)]
+struct RawEmbeddingResponse {
+    data: Vec<RawEmbedding>,
+    model: String,
+    usage: Usage,
+}
+
+#[derive(Deserialize)]
+struct RawEmbedding {
+    index: usize,
+    embedding: RawVector,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+impl RawVector {
+    fn decode(self) -> UtilsResult<Vec<f32>> {
+        match self {
+            RawVector::Float(vector) => Ok(vector),
+            RawVector::Base64(data) => {
+                let bytes = STANDARD.decode(data).map_err(|e| {
+                    InternalError::ConfigurationError(format!("invalid base64 embedding: {}", e))
+                })?;
+                Ok(bytes
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect())
+            }
+        }
+    }
+}
+
+// Builds embedding requests the way `AiAgent` builds chat requests. Large inputs are split
+// into several requests, the results are put back together in the order of the input.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Embeddings {
+    pub model: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dimensions: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding_format: Option<EncodingFormat>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    pub max_batch_size: usize,
+    pub max_batch_tokens: usize,
+
+    // overrides the retry policy of the client the requests are executed against
+    #[serde(skip)]
+    pub retry_policy: Option<RetryPolicy>,
+}
+
+impl Embeddings {
+    pub fn new(model: impl Into<String>) -> Self {
+        Self {
+            model: model.into(),
+            dimensions: None,
+            encoding_format: None,
+            user: None,
+            max_batch_size: MAX_BATCH_SIZE,
+            max_batch_tokens: MAX_BATCH_TOKENS,
+            retry_policy: None,
+        }
+    }
+
+    // only supported by the text-embedding-3 models
+    pub fn with_dimensions(mut self, dimensions: u32) -> Self {
+        self.dimensions = Some(dimensions);
+        self
+    }
+
+    pub fn with_encoding_format(mut self, encoding_format: EncodingFormat) -> Self {
+        self.encoding_format = Some(encoding_format);
+        self
+    }
+
+    pub fn with_user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size.max(1);
+        self
+    }
+
+    pub fn with_max_batch_tokens(mut self, max_batch_tokens: usize) -> Self {
+        self.max_batch_tokens = max_batch_tokens;
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = Some(retry_policy);
+        self
+    }
+
+    pub fn build_request(&self, input: EmbeddingInput) -> EmbeddingRequest {
+        EmbeddingRequest {
+            model: self.model.clone(),
+            input,
+            dimensions: self.dimensions,
+            encoding_format: self.encoding_format,
+            user: self.user.clone(),
+        }
+    }
+
+    pub async fn create(&self, input: impl Into<EmbeddingInput>) -> UtilsResult<EmbeddingResponse> {
+        self.create_with(&DEFAULT_CLIENT, input).await
+    }
+
+    pub async fn create_with(
+        &self,
+        client: &Client,
+        input: impl Into<EmbeddingInput>,
+    ) -> UtilsResult<EmbeddingResponse> {
+        let policy = self.retry_policy.as_ref().unwrap_or(client.retry_policy());
+
+        let mut response = EmbeddingResponse {
+            data: vec![],
+            model: self.model.clone(),
+            usage: Usage::default(),
+        };
+
+        for input in input
+            .into()
+            .batches(self.max_batch_size, self.max_batch_tokens)
+        {
+            let offset = response.data.len();
+            let request = self.build_request(input);
+            let res = client
+                .send(
+                    client.embeddings_request(&request)?,
+                    policy,
+                    || request.estimated_tokens(),
+                )
+                .await?;
+            let raw: RawEmbeddingResponse = serialize_response(res).await?;
+
+            let mut data = raw
+                .data
+                .into_iter()
+                .map(|embedding| {
+                    Ok(Embedding {
+                        index: offset + embedding.index,
+                        embedding: embedding.embedding.decode()?,
+                    })
+                })
+                .collect::<UtilsResult<Vec<Embedding>>>()?;
+            data.sort_by_key(|embedding| embedding.index);
+
+            response.data.extend(data);
+            response.model = raw.model;
+            response.usage.prompt_tokens += raw.usage.prompt_tokens;
+            response.usage.total_tokens += raw.usage.total_tokens;
+        }
+
+        Ok(response)
+    }
+}
+
+fn batch<T>(
+    items: Vec<T>,
+    tokens: impl Fn(&T) -> usize,
+    max_size: usize,
+    max_tokens: usize,
+) -> Vec<Vec<T>> {
+    let mut batches = vec![];
+    let mut current = vec![];
+    let mut current_tokens = 0;
+
+    for item in items {
+        let item_tokens = tokens(&item);
+        // an input over the limit on its own still gets a request, the api reports the error
+        if !current.is_empty()
+            && (current.len() >= max_size || current_tokens + item_tokens > max_tokens)
+        {
+            batches.push(std::mem::take(&mut current));
+            current_tokens = 0;
+        }
+
+        current_tokens += item_tokens;
+        current.push(item);
+    }
+
+    if !current.is_empty() {
+        batches.push(current);
+    }
+    batches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lengths(batches: &[Vec<Vec<u32>>]) -> Vec<usize> {
+        batches.iter().map(Vec::len).collect()
+    }
+
+    #[test]
+    fn splits_by_input_count() {
+        let batches = batch(vec![vec![1]; 5], Vec::len, 2, 100);
+        assert_eq!(lengths(&batches), vec![2, 2, 1]);
+    }
+
+    #[test]
+    fn splits_by_tokens() {
+        let items = vec![vec![1; 4], vec![2; 4], vec![3; 4], vec![4; 1]];
+        let batches = batch(items, Vec::len, 100, 9);
+        assert_eq!(lengths(&batches), vec![2, 2]);
+        assert_eq!(batches[1], vec![vec![3; 4], vec![4; 1]]);
+    }
+
+    #[test]
+    fn oversized_input_gets_its_own_batch() {
+        let items = vec![vec![1; 2], vec![2; 20], vec![3; 2]];
+        let batches = batch(items, Vec::len, 100, 10);
+        assert_eq!(lengths(&batches), vec![1, 1, 1]);
+        assert_eq!(batches[1], vec![vec![2; 20]]);
+    }
+
+    #[test]
+    fn keeps_the_input_order() {
+        let input = EmbeddingInput::TokenArrays((0..7).map(|i| vec![i]).collect());
+        let flattened: Vec<Vec<u32>> = input
+            .batches(3, 100)
+            .into_iter()
+            .flat_map(|batch| match batch {
+                EmbeddingInput::TokenArrays(arrays) => arrays,
+                _ => panic!("token arrays stay token arrays"),
+            })
+            .collect();
+        assert_eq!(flattened, (0..7).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn empty_input_has_no_batches() {
+        assert!(batch(Vec::<Vec<u32>>::new(), Vec::len, 2, 10).is_empty());
+    }
+}
@@ -1,6 +1,135 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
 use thiserror::Error;
 use serde::{Deserialize, Serialize};
 
+use crate::retry::parse_reset_duration;
+use crate::schema_validation::ArgumentErrors;
+use crate::strict_schema::StrictSchemaError;
+
+// The parts of an http response worth keeping around for debugging: the status and the
+// headers openai uses for request ids, rate limits and processing info.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseInfo {
+    pub status: Option<u16>,
+    pub headers: BTreeMap<String, String>,
+}
+
+impl ResponseInfo {
+    pub(crate) fn new(status: StatusCode, headers: &HeaderMap) -> Self {
+        let headers = headers
+            .iter()
+            .filter(|(name, _)| {
+                let name = name.as_str();
+                name == "x-request-id"
+                    || name == "content-type"
+                    || name == "retry-after"
+                    || name == "retry-after-ms"
+                    || name.starts_with("x-ratelimit-")
+                    || name.starts_with("openai-")
+            })
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+
+        Self {
+            status: Some(status.as_u16()),
+            headers,
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        self.header("x-request-id")
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimitHeaders> {
+        let number = |name: &str| self.header(name).and_then(|v| v.trim().parse::<u64>().ok());
+        let duration = |name: &str| self.header(name).and_then(parse_reset_duration);
+
+        let headers = RateLimitHeaders {
+            limit_requests: number("x-ratelimit-limit-requests"),
+            limit_tokens: number("x-ratelimit-limit-tokens"),
+            remaining_requests: number("x-ratelimit-remaining-requests"),
+            remaining_tokens: number("x-ratelimit-remaining-tokens"),
+            reset_requests: duration("x-ratelimit-reset-requests"),
+            reset_tokens: duration("x-ratelimit-reset-tokens"),
+        };
+
+        (headers != RateLimitHeaders::default()).then_some(headers)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RateLimitHeaders {
+    pub limit_requests: Option<u64>,
+    pub limit_tokens: Option<u64>,
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    pub reset_requests: Option<Duration>,
+    pub reset_tokens: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Connect,
+    FirstByte,
+    Idle,
+}
+
+impl fmt::Display for TimeoutKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimeoutKind::Connect => write!(f, "the connection"),
+            TimeoutKind::FirstByte => write!(f, "the first delta"),
+            TimeoutKind::Idle => write!(f, "the next delta"),
+        }
+    }
+}
+
+// Rough classification of a failure, mostly so callers can decide what to do about it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Authentication,
+    PermissionDenied,
+    NotFound,
+    RateLimit,
+    Quota,
+    ContextLengthExceeded,
+    ContentFilter,
+    InvalidRequest,
+    Server,
+    Connection,
+    Timeout,
+    Other,
+}
+
+// A response body that was neither the expected type nor an openai error envelope,
+// e.g. an html error page from a proxy.
+#[derive(Debug, Error)]
+pub struct UnexpectedResponse {
+    pub info: ResponseInfo,
+    pub body: String,
+    #[source]
+    pub source: serde_json::Error,
+}
+
+impl fmt::Display for UnexpectedResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "could not parse response body")?;
+        if let Some(status) = self.info.status {
+            write!(f, " (status {})", status)?;
+        }
+        write!(f, ": {}", self.source)
+    }
+}
+
 // Define an enum for internal errors.
 #[derive(Debug, Error)]
 pub enum InternalError {
@@ -11,28 +140,160 @@ pub enum InternalError {
     RequestBuildError(#[from] reqwest::Error),
 
     #[error("Event source error: {0}")]
-    EventSourceError(#[from] reqwest_eventsource::Error),
+    EventSourceError(Box<reqwest_eventsource::Error>),
 
     #[error("Serialization error: {0}")]
     SerializationError(#[from] serde_json::Error),
 
+    #[error("IO error: {0}")]
+    IoError(std::io::Error),
+
+    #[error("Unexpected response: {0}")]
+    UnexpectedResponse(Box<UnexpectedResponse>),
+
+    #[error("stream closed before the response was complete")]
+    StreamClosed,
+
+    #[error("stream sent events before it was opened")]
+    StreamNotOpened,
+
+    #[error("timed out waiting for {0}")]
+    Timeout(TimeoutKind),
+
+    #[error("no function named {0} is registered")]
+    UnknownFunction(String),
+
+    #[error("invalid function name {0:?}, names must match ^[a-zA-Z0-9_-]{{1,64}}$")]
+    InvalidFunctionName(String),
+
+    #[error("{0}")]
+    InvalidArguments(Box<ArgumentErrors>),
+
+    #[error("{0}")]
+    UnsupportedSchema(StrictSchemaError),
+
+    #[error("the model refused to answer: {0}")]
+    Refusal(String),
+
+    #[error("the response contained no choices")]
+    NoChoices,
+
+    #[error("the response contained no embeddings")]
+    NoEmbeddings,
+
+    #[error("embedding has {1} dimensions, expected {0}")]
+    DimensionMismatch(usize, usize),
+
+    #[error("no final answer after {0} iterations")]
+    MaxIterationsExceeded(usize),
+
     #[error("no deltas were received, cannot construct chat")]
     NoDeltasReceived,
+
+    #[error("choice {0} of the stream never received a {1}, cannot construct chat")]
+    IncompleteChoice(i64, &'static str),
+}
+
+impl From<reqwest_eventsource::Error> for InternalError {
+    fn from(e: reqwest_eventsource::Error) -> Self {
+        InternalError::EventSourceError(Box::new(e))
+    }
 }
 
 // Define an enum for OpenAI API errors.
+// The response fields aren't part of the error body, they are filled in from the http response.
 #[derive(Debug, Error, Clone, Deserialize, Serialize)]
+#[serde(untagged)]
 pub enum OpenAIError {
     #[error("OpenAI API error: {message}")]
     ApiError {
         message: String,
-        #[serde(rename = "type")]
+        #[serde(rename = "type", default)]
         error_type: String,
         param: Option<String>,
         code: Option<String>,
+
+        #[serde(skip)]
+        response: Option<Box<ResponseInfo>>,
     },
 }
 
+impl OpenAIError {
+    pub(crate) fn with_response_info(mut self, info: &ResponseInfo) -> Self {
+        let OpenAIError::ApiError { response, .. } = &mut self;
+        *response = Some(Box::new(info.clone()));
+        self
+    }
+
+    pub fn response_info(&self) -> Option<&ResponseInfo> {
+        let OpenAIError::ApiError { response, .. } = self;
+        response.as_deref()
+    }
+
+    pub fn message(&self) -> &str {
+        let OpenAIError::ApiError { message, .. } = self;
+        message
+    }
+
+    pub fn code(&self) -> Option<&str> {
+        let OpenAIError::ApiError { code, .. } = self;
+        code.as_deref()
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        self.response_info().and_then(|info| info.status)
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        self.response_info().and_then(ResponseInfo::request_id)
+    }
+
+    pub fn rate_limit(&self) -> Option<RateLimitHeaders> {
+        self.response_info().and_then(ResponseInfo::rate_limit)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        let OpenAIError::ApiError {
+            message,
+            error_type,
+            code,
+            ..
+        } = self;
+        let code = code.as_deref().unwrap_or_default();
+
+        if code == "context_length_exceeded" || message.contains("maximum context length") {
+            return ErrorKind::ContextLengthExceeded;
+        }
+        if code == "insufficient_quota" || error_type == "insufficient_quota" {
+            return ErrorKind::Quota;
+        }
+        if code == "content_filter" || code == "content_policy_violation" {
+            return ErrorKind::ContentFilter;
+        }
+        if code == "rate_limit_exceeded" {
+            return ErrorKind::RateLimit;
+        }
+        if code == "invalid_api_key" || error_type == "authentication_error" {
+            return ErrorKind::Authentication;
+        }
+        if error_type == "server_error" {
+            return ErrorKind::Server;
+        }
+
+        match self.status() {
+            Some(401) => ErrorKind::Authentication,
+            Some(403) => ErrorKind::PermissionDenied,
+            Some(404) => ErrorKind::NotFound,
+            Some(408) => ErrorKind::Connection,
+            Some(429) => ErrorKind::RateLimit,
+            Some(400..=499) => ErrorKind::InvalidRequest,
+            Some(500..=599) => ErrorKind::Server,
+            _ if error_type == "invalid_request_error" => ErrorKind::InvalidRequest,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
 // Define a wrapper enum for all types of errors.
 #[derive(Debug, Error)]
 pub enum Error {
@@ -43,5 +304,267 @@ pub enum Error {
     OpenAI(#[from] OpenAIError),
 }
 
+impl Error {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::OpenAI(e) => e.kind(),
+            Error::Internal(InternalError::RequestBuildError(e)) if e.is_connect() || e.is_timeout() => {
+                ErrorKind::Connection
+            }
+            Error::Internal(InternalError::StreamClosed) => ErrorKind::Connection,
+            Error::Internal(InternalError::Timeout(_)) => ErrorKind::Timeout,
+            // gateways and proxies answer with html or plain text, the status still counts
+            Error::Internal(InternalError::UnexpectedResponse(e)) => match e.info.status {
+                Some(408) => ErrorKind::Connection,
+                Some(429) => ErrorKind::RateLimit,
+                Some(500..=599) => ErrorKind::Server,
+                _ => ErrorKind::Other,
+            },
+            Error::Internal(_) => ErrorKind::Other,
+        }
+    }
+
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            Error::OpenAI(e) => e.status(),
+            Error::Internal(InternalError::UnexpectedResponse(e)) => e.info.status,
+            Error::Internal(InternalError::RequestBuildError(e)) => e.status().map(|s| s.as_u16()),
+            Error::Internal(_) => None,
+        }
+    }
+
+    pub fn request_id(&self) -> Option<&str> {
+        match self {
+            Error::OpenAI(e) => e.request_id(),
+            Error::Internal(InternalError::UnexpectedResponse(e)) => e.info.request_id(),
+            Error::Internal(_) => None,
+        }
+    }
+
+    // rate limits and server side failures, but not an exhausted quota
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.kind(),
+            ErrorKind::RateLimit | ErrorKind::Server | ErrorKind::Connection | ErrorKind::Timeout
+        )
+    }
+
+    pub fn is_timeout(&self) -> bool {
+        self.kind() == ErrorKind::Timeout
+    }
+
+    pub fn argument_errors(&self) -> Option<&ArgumentErrors> {
+        match self {
+            Error::Internal(InternalError::InvalidArguments(e)) => Some(e),
+            _ => None,
+        }
+    }
+
+    pub fn is_context_length_exceeded(&self) -> bool {
+        self.kind() == ErrorKind::ContextLengthExceeded
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        self.kind() == ErrorKind::RateLimit
+    }
+
+    pub fn is_quota_exceeded(&self) -> bool {
+        self.kind() == ErrorKind::Quota
+    }
+
+    pub fn is_authentication(&self) -> bool {
+        self.kind() == ErrorKind::Authentication
+    }
+
+    pub fn is_content_filtered(&self) -> bool {
+        self.kind() == ErrorKind::ContentFilter
+    }
+}
+
 // Convenience type alias for `Result` with our custom error type.
 pub type UtilsResult<T> = Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(status: u16) -> ResponseInfo {
+        let mut info = ResponseInfo {
+            status: Some(status),
+            ..ResponseInfo::default()
+        };
+        info.headers
+            .insert("x-request-id".to_string(), format!("req_{}", status));
+        info
+    }
+
+    fn api_error(status: u16, error_type: &str, code: Option<&str>, message: &str) -> Error {
+        let body = serde_json::json!({
+            "message": message,
+            "type": error_type,
+            "param": null,
+            "code": code,
+        });
+        serde_json::from_value::<OpenAIError>(body)
+            .unwrap()
+            .with_response_info(&info(status))
+            .into()
+    }
+
+    fn unexpected(status: u16, body: &str) -> Error {
+        let source = serde_json::from_str::<serde_json::Value>(body).unwrap_err();
+        InternalError::UnexpectedResponse(Box::new(UnexpectedResponse {
+            info: info(status),
+            body: body.to_string(),
+            source,
+        }))
+        .into()
+    }
+
+    #[test]
+    fn classifies_api_errors() {
+        let cases = [
+            (
+                api_error(
+                    401,
+                    "invalid_request_error",
+                    Some("invalid_api_key"),
+                    "bad key",
+                ),
+                ErrorKind::Authentication,
+                false,
+            ),
+            (
+                api_error(403, "invalid_request_error", None, "not allowed"),
+                ErrorKind::PermissionDenied,
+                false,
+            ),
+            (
+                api_error(
+                    404,
+                    "invalid_request_error",
+                    Some("model_not_found"),
+                    "no model",
+                ),
+                ErrorKind::NotFound,
+                false,
+            ),
+            (
+                api_error(
+                    429,
+                    "insufficient_quota",
+                    Some("insufficient_quota"),
+                    "quota",
+                ),
+                ErrorKind::Quota,
+                false,
+            ),
+            (
+                api_error(429, "requests", Some("rate_limit_exceeded"), "slow down"),
+                ErrorKind::RateLimit,
+                true,
+            ),
+            (
+                api_error(
+                    400,
+                    "invalid_request_error",
+                    Some("context_length_exceeded"),
+                    "too long",
+                ),
+                ErrorKind::ContextLengthExceeded,
+                false,
+            ),
+            (
+                api_error(400, "invalid_request_error", None, "bad request"),
+                ErrorKind::InvalidRequest,
+                false,
+            ),
+            (
+                api_error(500, "server_error", None, "oops"),
+                ErrorKind::Server,
+                true,
+            ),
+        ];
+
+        for (e, kind, retryable) in cases {
+            let status = e.status().unwrap();
+            assert_eq!(e.kind(), kind, "status {}", status);
+            assert_eq!(e.is_retryable(), retryable, "status {}", status);
+            assert_eq!(e.request_id(), Some(format!("req_{}", status).as_str()));
+            assert_eq!(
+                e.is_context_length_exceeded(),
+                kind == ErrorKind::ContextLengthExceeded
+            );
+            assert!(!e.is_timeout());
+        }
+    }
+
+    #[test]
+    fn context_length_is_recognized_by_message() {
+        let e = api_error(
+            400,
+            "invalid_request_error",
+            None,
+            "This model's maximum context length is 8192 tokens",
+        );
+        assert!(e.is_context_length_exceeded());
+    }
+
+    #[test]
+    fn classifies_unexpected_responses_by_status() {
+        let cases = [
+            (
+                unexpected(429, "Too Many Requests"),
+                ErrorKind::RateLimit,
+                true,
+            ),
+            (
+                unexpected(408, "<html>Request Timeout</html>"),
+                ErrorKind::Connection,
+                true,
+            ),
+            (
+                unexpected(502, "<html>Bad Gateway</html>"),
+                ErrorKind::Server,
+                true,
+            ),
+            (
+                unexpected(503, "upstream connect error"),
+                ErrorKind::Server,
+                true,
+            ),
+            (
+                unexpected(404, "<html>Not Found</html>"),
+                ErrorKind::Other,
+                false,
+            ),
+            (unexpected(200, "{\"id\": "), ErrorKind::Other, false),
+        ];
+
+        for (e, kind, retryable) in cases {
+            let status = e.status().unwrap();
+            assert_eq!(e.kind(), kind, "status {}", status);
+            assert_eq!(e.is_retryable(), retryable, "status {}", status);
+            assert_eq!(e.request_id(), Some(format!("req_{}", status).as_str()));
+        }
+    }
+
+    #[test]
+    fn classifies_internal_errors() {
+        let timeout: Error = InternalError::Timeout(TimeoutKind::Idle).into();
+        assert_eq!(timeout.kind(), ErrorKind::Timeout);
+        assert!(timeout.is_timeout());
+        assert!(timeout.is_retryable());
+        assert_eq!(timeout.status(), None);
+        assert_eq!(timeout.request_id(), None);
+
+        let closed: Error = InternalError::StreamClosed.into();
+        assert_eq!(closed.kind(), ErrorKind::Connection);
+        assert!(closed.is_retryable());
+        assert!(!closed.is_timeout());
+
+        let unknown: Error = InternalError::UnknownFunction("f".to_string()).into();
+        assert_eq!(unknown.kind(), ErrorKind::Other);
+        assert!(!unknown.is_retryable());
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+
+use futures_util::future::BoxFuture;
+use futures_util::FutureExt;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::error::{InternalError, UtilsResult};
+use crate::{Function, FunctionCall};
+
+// Implemented by the tools generated with `#[openai_tool]` (`macros` feature).
+pub trait ToolFunction {
+    fn definition() -> Function;
+
+    fn register(registry: &mut FunctionRegistry) -> Function;
+}
+
+type Handler = Arc<dyn Fn(&FunctionCall) -> BoxFuture<'static, UtilsResult<String>> + Send + Sync>;
+
+#[derive(Clone)]
+struct RegisteredFunction {
+    definition: Function,
+    handler: Handler,
+}
+
+// Keeps the callables next to their definitions so calls requested by the model can be
+// executed. Results are serialized to json, plain strings are passed through as they are.
+#[derive(Clone, Default)]
+pub struct FunctionRegistry {
+    functions: BTreeMap<String, RegisteredFunction>,
+}
+
+impl FunctionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<FunctionArgs, Func, T>(
+        &mut self,
+        function: Func,
+        function_name: &str,
+    ) -> Function
+    where
+        FunctionArgs: JsonSchema + DeserializeOwned + Send + 'static,
+        Func: Fn(FunctionArgs) -> T + Send + Sync + 'static,
+        T: Serialize,
+    {
+        self.register_with(Function::from(&function, function_name), function)
+    }
+
+    // like `register`, with a definition built by hand, e.g. to give it a description
+    pub fn register_with<FunctionArgs, Func, T>(
+        &mut self,
+        definition: Function,
+        function: Func,
+    ) -> Function
+    where
+        FunctionArgs: JsonSchema + DeserializeOwned + Send + 'static,
+        Func: Fn(FunctionArgs) -> T + Send + Sync + 'static,
+        T: Serialize,
+    {
+        let handler: Handler = Arc::new(move |call: &FunctionCall| {
+            let result = call
+                .parse_arguments::<FunctionArgs>()
+                .and_then(|args| to_content(function(args)));
+            async move { result }.boxed()
+        });
+
+        self.insert(definition, handler)
+    }
+
+    pub fn register_async<FunctionArgs, Func, Fut, T>(
+        &mut self,
+        function: Func,
+        function_name: &str,
+    ) -> Function
+    where
+        FunctionArgs: JsonSchema + DeserializeOwned + Send + 'static,
+        Func: Fn(FunctionArgs) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = T> + Send + 'static,
+        T: Serialize,
+    {
+        self.register_async_with(Function::from(&function, function_name), function)
+    }
+
+    pub fn register_async_with<FunctionArgs, Func, Fut, T>(
+        &mut self,
+        definition: Function,
+        function: Func,
+    ) -> Function
+    where
+        FunctionArgs: JsonSchema + DeserializeOwned + Send + 'static,
+        Func: Fn(FunctionArgs) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = T> + Send + 'static,
+        T: Serialize,
+    {
+        let function = Arc::new(function);
+        let handler: Handler = Arc::new(move |call: &FunctionCall| {
+            let function = function.clone();
+            let args = call.parse_arguments::<FunctionArgs>();
+            async move { to_content(function(args?).await) }.boxed()
+        });
+
+        self.insert(definition, handler)
+    }
+
+    fn insert(&mut self, definition: Function, handler: Handler) -> Function {
+        self.functions.insert(
+            definition.name.clone(),
+            RegisteredFunction {
+                definition: definition.clone(),
+                handler,
+            },
+        );
+        definition
+    }
+
+    pub fn remove(&mut self, function_name: &str) -> Option<Function> {
+        self.functions
+            .remove(function_name)
+            .map(|function| function.definition)
+    }
+
+    pub fn contains(&self, function_name: &str) -> bool {
+        self.functions.contains_key(function_name)
+    }
+
+    pub fn get(&self, function_name: &str) -> Option<&Function> {
+        self.functions
+            .get(function_name)
+            .map(|function| &function.definition)
+    }
+
+    pub fn definitions(&self) -> Vec<Function> {
+        self.functions
+            .values()
+            .map(|function| function.definition.clone())
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty()
+    }
+
+    // runs the function and returns its serialized result
+    pub async fn call(&self, call: &FunctionCall) -> UtilsResult<String> {
+        let function = self
+            .functions
+            .get(&call.name)
+            .ok_or_else(|| InternalError::UnknownFunction(call.name.clone()))?;
+
+        (function.handler)(call).await
+    }
+}
+
+impl fmt::Debug for FunctionRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.functions.keys()).finish()
+    }
+}
+
+fn to_content<T: Serialize>(value: T) -> UtilsResult<String> {
+    match serde_json::to_value(value).map_err(InternalError::SerializationError)? {
+        Value::String(s) => Ok(s),
+        value => Ok(value.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(serde_derive::Deserialize, serde_derive::Serialize, JsonSchema)]
+    struct AddArgs {
+        a: i64,
+        b: i64,
+    }
+
+    #[derive(serde_derive::Serialize)]
+    struct Weather {
+        city: String,
+        celsius: f64,
+    }
+
+    fn call(name: &str, arguments: &str) -> FunctionCall {
+        FunctionCall {
+            name: name.to_string(),
+            arguments: arguments.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_sync_functions() {
+        let mut registry = FunctionRegistry::new();
+        let definition = registry.register(|args: AddArgs| args.a + args.b, "add");
+
+        assert_eq!(definition.name, "add");
+        assert!(registry.contains("add"));
+        assert_eq!(
+            registry
+                .call(&call("add", r#"{"a":2,"b":3}"#))
+                .await
+                .unwrap(),
+            "5"
+        );
+    }
+
+    #[tokio::test]
+    async fn calls_async_functions() {
+        let mut registry = FunctionRegistry::new();
+        registry.register_async(
+            |args: AddArgs| async move {
+                tokio::task::yield_now().await;
+                args.a * args.b
+            },
+            "multiply",
+        );
+
+        assert_eq!(
+            registry
+                .call(&call("multiply", r#"{"a":2,"b":3}"#))
+                .await
+                .unwrap(),
+            "6"
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_function_is_an_error() {
+        let registry = FunctionRegistry::new();
+        let e = registry.call(&call("missing", "{}")).await.unwrap_err();
+        assert!(
+            matches!(e, Error::Internal(InternalError::UnknownFunction(name)) if name == "missing")
+        );
+    }
+
+    #[tokio::test]
+    async fn removed_functions_are_unknown() {
+        let mut registry = FunctionRegistry::new();
+        registry.register(|args: AddArgs| args.a + args.b, "add");
+        assert_eq!(registry.remove("add").unwrap().name, "add");
+
+        assert!(registry.is_empty());
+        let e = registry
+            .call(&call("add", r#"{"a":1,"b":1}"#))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e,
+            Error::Internal(InternalError::UnknownFunction(_))
+        ));
+    }
+
+    #[test]
+    fn strings_pass_through_and_everything_else_is_json() {
+        assert_eq!(to_content("it's sunny").unwrap(), "it's sunny");
+        assert_eq!(to_content(42).unwrap(), "42");
+        assert_eq!(to_content(true).unwrap(), "true");
+        assert_eq!(to_content(Option::<i64>::None).unwrap(), "null");
+        assert_eq!(to_content(vec!["a", "b"]).unwrap(), r#"["a","b"]"#);
+        assert_eq!(
+            to_content(Weather {
+                city: "Paris".to_string(),
+                celsius: 21.5,
+            })
+            .unwrap(),
+            r#"{"celsius":21.5,"city":"Paris"}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn handlers_only_see_validated_arguments() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut registry = FunctionRegistry::new();
+        let counter = calls.clone();
+        registry.register(
+            move |args: AddArgs| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                args.a + args.b
+            },
+            "add",
+        );
+
+        for arguments in [r#"{"a":1}"#, r#"{"a":"1","b":2}"#, "not json"] {
+            let e = registry.call(&call("add", arguments)).await.unwrap_err();
+            let errors = e.argument_errors().expect(arguments);
+            assert_eq!(errors.function_name, "add");
+            assert!(!errors.violations.is_empty());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        assert_eq!(
+            registry
+                .call(&call("add", r#"{"a":1,"b":2}"#))
+                .await
+                .unwrap(),
+            "3"
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn async_handlers_only_see_validated_arguments() {
+        let mut registry = FunctionRegistry::new();
+        registry.register_async(|args: AddArgs| async move { args.a + args.b }, "add");
+
+        let e = registry.call(&call("add", r#"{"b":2}"#)).await.unwrap_err();
+        assert!(e.argument_errors().is_some());
+    }
+}
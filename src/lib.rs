@@ -1,33 +1,74 @@
 #![allow(dead_code)]
-#![feature(type_name_of_val)]
-#![feature(associated_type_defaults)]
-#![feature(async_fn_in_trait)]
 
+mod agent_run;
+mod audio;
 mod chat_completion;
 mod chat_completion_delta;
 mod chat_completion_request;
+mod client;
+mod content;
+mod embeddings;
 mod error;
+mod function_registry;
+mod partial_json;
+mod rag;
+mod rate_limit;
+mod retry;
+mod schema_validation;
+mod strict_schema;
+#[cfg(test)]
+mod test_server;
+mod vector_store;
+
+#[cfg(feature = "macros")]
+pub use openai_utils_macros::openai_tool;
+
+// used by the code `#[openai_tool]` generates, so users don't need the crates themselves
+#[doc(hidden)]
+pub mod __private {
+    pub use schemars;
+    pub use serde;
+}
 
 use lazy_static::lazy_static;
 #[allow(unused_imports)]
 use log::{debug, error, info, trace, warn};
-use std::{
-    any::type_name_of_val,
-    sync::{Arc, RwLock},
-};
+use std::sync::{Arc, RwLock};
 
 use schemars::{schema_for, JsonSchema};
 use serde_derive::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
 use serde_json::Value;
 pub use {
+    agent_run::{RunOptions, RunStep, RunTranscript},
+    audio::{AudioConfig, AudioDelta, InputAudio, MessageAudio},
     chat_completion::ChatCompletion as Chat,
     chat_completion_delta::ChatCompletionDelta as ChatDelta, chat_completion_delta::DeltaReceiver,
+    chat_completion_delta::{CancelHandle, StreamTimeouts, ToolCallAccumulator},
     chat_completion_request::AiAgent,
     chat_completion_request::ChatCompletionRequest as ChatRequest,
+    client::{AzureConfig, Client, Endpoint},
+    content::{image_tokens, Content, ContentPart, ImageDetail, ImageUrl},
+    embeddings::{
+        Embedding, EmbeddingInput, EmbeddingRequest, EmbeddingResponse, Embeddings, EncodingFormat,
+    },
+    error::{
+        Error, ErrorKind, InternalError, OpenAIError, RateLimitHeaders, ResponseInfo, TimeoutKind,
+        UnexpectedResponse, UtilsResult,
+    },
+    function_registry::{FunctionRegistry, ToolFunction},
+    partial_json::{parse_partial, parse_partial_json},
+    rag::{Citation, ContextRole, Rag},
+    rate_limit::RateLimiter,
+    retry::RetryPolicy,
+    schema_validation::{validate, ArgumentErrors, SchemaViolation},
+    strict_schema::{to_strict_schema, StrictSchemaError},
+    vector_store::{Document, Filter, SearchResult, Similarity, VectorStore},
 };
 
 lazy_static! {
     static ref OPENAI_API_KEY: Arc<RwLock<Option<String>>> = Arc::new(RwLock::new(None));
+    static ref DEFAULT_CLIENT: Client = Client::new();
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -35,13 +76,25 @@ pub struct Message {
     pub role: String,
 
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub content: Option<String>,
+    pub content: Option<Content>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub name: Option<String>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     pub function_call: Option<FunctionCall>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<MessageAudio>,
 }
 
 impl Message {
@@ -51,18 +104,60 @@ impl Message {
             content: None,
             name: None,
             function_call: None,
+            tool_calls: None,
+            tool_call_id: None,
+            refusal: None,
+            audio: None,
         }
     }
 
-    pub fn with_content(mut self, content: impl Into<String>) -> Self {
+    // the result of a tool call, to be sent back to the model
+    pub fn tool(tool_call_id: impl Into<String>, content: impl Into<String>) -> Self {
+        Self::new("tool")
+            .with_tool_call_id(tool_call_id)
+            .with_content(content.into())
+    }
+
+    pub fn with_content(mut self, content: impl Into<Content>) -> Self {
         self.content = Some(content.into());
         self
     }
 
+    // appends a part, existing text content becomes the first part
+    pub fn with_part(mut self, part: ContentPart) -> Self {
+        match &mut self.content {
+            Some(content) => content.push(part),
+            None => self.content = Some(Content::Parts(vec![part])),
+        }
+        self
+    }
+
+    pub fn with_text(self, text: impl Into<String>) -> Self {
+        self.with_part(ContentPart::text(text))
+    }
+
+    pub fn with_image(self, image_url: ImageUrl) -> Self {
+        self.with_part(ContentPart::image(image_url))
+    }
+
+    pub fn with_audio(self, input_audio: InputAudio) -> Self {
+        self.with_part(ContentPart::audio(input_audio))
+    }
+
     pub fn with_name(mut self, name: impl Into<String>) -> Self {
         self.name = Some(name.into());
         self
     }
+
+    pub fn with_tool_calls(mut self, tool_calls: Vec<ToolCall>) -> Self {
+        self.tool_calls = Some(tool_calls);
+        self
+    }
+
+    pub fn with_tool_call_id(mut self, tool_call_id: impl Into<String>) -> Self {
+        self.tool_call_id = Some(tool_call_id.into());
+        self
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -71,6 +166,49 @@ pub struct FunctionCall {
     pub arguments: String,
 }
 
+impl FunctionCall {
+    // best effort view of arguments that are still being streamed
+    pub fn partial_arguments(&self) -> Option<Value> {
+        parse_partial_json(&self.arguments)
+    }
+
+    pub fn parse_partial_arguments<T: DeserializeOwned>(&self) -> Option<T> {
+        parse_partial(&self.arguments)
+    }
+
+    // checks the arguments against the same schema `Function::from` sends to the model before
+    // deserializing them, so every problem is reported at once instead of the first serde error
+    pub fn parse_arguments<T: DeserializeOwned + JsonSchema>(&self) -> UtilsResult<T> {
+        let invalid = |violations: Vec<SchemaViolation>| {
+            InternalError::InvalidArguments(Box::new(ArgumentErrors {
+                function_name: self.name.clone(),
+                violations,
+            }))
+        };
+        let parse_error = |e: serde_json::Error| {
+            invalid(vec![SchemaViolation {
+                path: String::new(),
+                message: e.to_string(),
+            }])
+        };
+
+        // functions without arguments sometimes get an empty string instead of `{}`
+        let arguments = match self.arguments.trim() {
+            "" => "{}",
+            arguments => arguments,
+        };
+
+        let value: Value = serde_json::from_str(arguments).map_err(parse_error)?;
+        let schema = serde_json::to_value(schema_for!(T)).map_err(InternalError::SerializationError)?;
+        let violations = validate(&schema, &value);
+        if !violations.is_empty() {
+            Err(invalid(violations))?
+        }
+
+        Ok(serde_json::from_value(value).map_err(parse_error)?)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Function {
     pub name: String,
@@ -78,28 +216,221 @@ pub struct Function {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
     pub parameters: Value,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
 }
 
 impl Function {
-    pub fn from<FunctionArgs, Func, T>(function: &Func, function_name: &str) -> Self
+    // definition for the arguments `FunctionArgs`, the description defaults to the doc comment
+    // of the struct while the docs of its fields end up in the parameter schema
+    pub fn new<FunctionArgs: JsonSchema>(name: impl Into<String>) -> Self {
+        let name = name.into();
+        let parameters = serde_json::to_value(schema_for!(FunctionArgs))
+            .unwrap_or_else(|_| panic!("Failed to serialize schema for function {}", name));
+        Self {
+            name,
+            description: match parameters.get("description") {
+                Some(Value::String(s)) => Some(s.clone()),
+                _ => None,
+            },
+            parameters,
+            strict: None,
+        }
+    }
+
+    pub fn from<FunctionArgs, Func, T>(_function: &Func, function_name: &str) -> Self
     where
         FunctionArgs: JsonSchema,
         Func: FnMut(FunctionArgs) -> T,
     {
-        let schema = schema_for!(FunctionArgs);
-        let parameters = serde_json::to_value(schema)
-            .unwrap_or_else(|_| panic!("Failed to serialize schema for function {}", function_name));
+        Self::new::<FunctionArgs>(function_name)
+    }
+
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    pub fn with_description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    // openai only accepts names matching `^[a-zA-Z0-9_-]{1,64}$`
+    pub fn validate(&self) -> UtilsResult<()> {
+        let valid = (1..=64).contains(&self.name.len())
+            && self
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+        match valid {
+            true => Ok(()),
+            false => Err(InternalError::InvalidFunctionName(self.name.clone()))?,
+        }
+    }
+
+    // rewrites the parameters for strict mode, the model then always sticks to the schema
+    pub fn into_strict(mut self) -> UtilsResult<Self> {
+        self.parameters = to_strict_schema(self.parameters).map_err(InternalError::UnsupportedSchema)?;
+        self.strict = Some(true);
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tool {
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: Function,
+}
+
+impl Tool {
+    pub fn function(function: Function) -> Self {
         Self {
-            name: function_name.to_string(),
-            description: match parameters.get("description") {
+            tool_type: "function".to_string(),
+            function,
+        }
+    }
+
+    pub fn from<FunctionArgs, Func, T>(function: &Func, function_name: &str) -> Self
+    where
+        FunctionArgs: JsonSchema,
+        Func: FnMut(FunctionArgs) -> T,
+    {
+        Self::function(Function::from(function, function_name))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+
+    #[serde(rename = "type")]
+    pub tool_type: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "ToolChoiceRepr", try_from = "ToolChoiceRepr")]
+pub enum ToolChoice {
+    Auto,
+    None,
+    Required,
+    Function(String),
+}
+
+// the wire format of `ToolChoice`, either a plain mode string or a specific function
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum ToolChoiceRepr {
+    Mode(String),
+    Function {
+        #[serde(rename = "type")]
+        tool_type: String,
+        function: ToolChoiceFunction,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct ToolChoiceFunction {
+    name: String,
+}
+
+impl From<ToolChoice> for ToolChoiceRepr {
+    fn from(choice: ToolChoice) -> Self {
+        match choice {
+            ToolChoice::Auto => ToolChoiceRepr::Mode("auto".to_string()),
+            ToolChoice::None => ToolChoiceRepr::Mode("none".to_string()),
+            ToolChoice::Required => ToolChoiceRepr::Mode("required".to_string()),
+            ToolChoice::Function(name) => ToolChoiceRepr::Function {
+                tool_type: "function".to_string(),
+                function: ToolChoiceFunction { name },
+            },
+        }
+    }
+}
+
+impl TryFrom<ToolChoiceRepr> for ToolChoice {
+    type Error = String;
+
+    fn try_from(repr: ToolChoiceRepr) -> Result<Self, Self::Error> {
+        match repr {
+            ToolChoiceRepr::Mode(mode) => match mode.as_str() {
+                "auto" => Ok(ToolChoice::Auto),
+                "none" => Ok(ToolChoice::None),
+                "required" => Ok(ToolChoice::Required),
+                _ => Err(format!("unknown tool choice: {}", mode)),
+            },
+            ToolChoiceRepr::Function { function, .. } => Ok(ToolChoice::Function(function.name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    Text,
+    JsonObject,
+    JsonSchema { json_schema: JsonSchemaFormat },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonSchemaFormat {
+    pub name: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub schema: Value,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict: Option<bool>,
+}
+
+impl JsonSchemaFormat {
+    // named after the type, the description is taken from its doc comment if it has one
+    pub fn of<T: JsonSchema>() -> Self {
+        let schema = serde_json::to_value(schema_for!(T))
+            .unwrap_or_else(|_| panic!("Failed to serialize schema for {}", T::schema_name()));
+
+        let name: String = T::schema_name()
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+                true => c,
+                false => '_',
+            })
+            .take(64)
+            .collect();
+
+        JsonSchemaFormat {
+            name,
+            description: match schema.get("description") {
                 Some(Value::String(s)) => Some(s.clone()),
                 _ => None,
             },
-            parameters,
+            schema,
+            strict: None,
         }
     }
 }
 
+impl ResponseFormat {
+    pub fn json_schema<T: JsonSchema>() -> Self {
+        ResponseFormat::JsonSchema {
+            json_schema: JsonSchemaFormat::of::<T>(),
+        }
+    }
+
+    // like `json_schema`, with the schema rewritten for strict mode
+    pub fn strict_json_schema<T: JsonSchema>() -> UtilsResult<Self> {
+        let mut json_schema = JsonSchemaFormat::of::<T>();
+        json_schema.schema = to_strict_schema(json_schema.schema).map_err(InternalError::UnsupportedSchema)?;
+        json_schema.strict = Some(true);
+        Ok(ResponseFormat::JsonSchema { json_schema })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Choice {
     pub index: i64,
@@ -123,6 +454,12 @@ pub struct Delta {
     pub content: Option<String>,
 
     pub function_call: Option<FunctionCallDelta>,
+
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+
+    pub refusal: Option<String>,
+
+    pub audio: Option<AudioDelta>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -131,9 +468,26 @@ pub struct FunctionCallDelta {
     pub arguments: Option<String>,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ToolCallDelta {
+    // openai always sends it, some compatible servers don't
+    #[serde(default)]
+    pub index: Option<usize>,
+
+    pub id: Option<String>,
+
+    #[serde(rename = "type")]
+    pub tool_type: Option<String>,
+
+    pub function: Option<FunctionCallDelta>,
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Usage {
     pub prompt_tokens: u64,
+
+    // embeddings don't report completion tokens
+    #[serde(default)]
     pub completion_tokens: u64,
     pub total_tokens: u64,
 }
@@ -143,20 +497,44 @@ pub fn api_key(api_key: String) {
     *key = Some(api_key);
 }
 
-#[derive(Default, Debug, Clone, JsonSchema)]
+#[derive(Default, Debug, Clone, JsonSchema, Deserialize)]
 #[schemars(description = "this function takes no arguments")]
 pub struct NoArgs {
+    #[serde(skip)]
     _unused: (),
 }
 
 pub fn calculate_message_tokens(message: &Message) -> usize {
-    let bpe = tiktoken_rs::cl100k_base().unwrap();
+    // building the tokenizer is expensive, it's shared between calls
+    let bpe = tiktoken_rs::cl100k_base_singleton();
+    let bpe = bpe.lock();
+
+    let calls = message
+        .function_call
+        .iter()
+        .chain(message.tool_calls.iter().flatten().map(|call| &call.function))
+        .fold(0, |acc, call| {
+            acc + bpe.encode_with_special_tokens(&call.name).len()
+                + bpe.encode_with_special_tokens(&call.arguments).len()
+        });
+
+    let content = match &message.content {
+        None => 0,
+        Some(Content::Text(text)) => bpe.encode_with_special_tokens(text).len(),
+        Some(Content::Parts(parts)) => parts.iter().fold(0, |acc, part| match part {
+            ContentPart::Text { text } => acc + bpe.encode_with_special_tokens(text).len(),
+            ContentPart::ImageUrl { image_url } => acc + image_url.estimated_tokens(),
+            // the cost of audio depends on its length, which isn't known without decoding it
+            ContentPart::InputAudio { .. } => acc,
+        }),
+    };
 
-    bpe.encode_with_special_tokens(&message.content.as_ref().unwrap_or(&"".to_string())).len()
+    content + calls
 }
 
 pub fn calculate_tokens(s: &str) -> usize {
-    let bpe = tiktoken_rs::cl100k_base().unwrap();
+    let bpe = tiktoken_rs::cl100k_base_singleton();
+    let bpe = bpe.lock();
 
     bpe.encode_with_special_tokens(s).len()
 }
@@ -0,0 +1,397 @@
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Number, Value};
+
+// the input comes from the model, so nesting is limited like serde_json does instead of
+// risking a stack overflow
+const MAX_DEPTH: usize = 128;
+
+// Parses json that may be cut off anywhere, like the arguments of a function call that is
+// still being streamed. Open strings, arrays and objects are closed, a truncated literal or
+// number is completed where possible and a key without a value yet is left out.
+// Returns `None` if there is no value yet or the input isn't the beginning of valid json.
+pub fn parse_partial_json(input: &str) -> Option<Value> {
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        position: 0,
+        depth: 0,
+    };
+
+    let value = parser.parse_value().ok()??;
+    parser.skip_whitespace();
+    match parser.at_end() {
+        true => Some(value),
+        // trailing garbage
+        false => None,
+    }
+}
+
+// `parse_partial_json` deserialized into `T`, which should tolerate missing fields,
+// e.g. by making them `Option`s or `#[serde(default)]`
+pub fn parse_partial<T: DeserializeOwned>(input: &str) -> Option<T> {
+    serde_json::from_value(parse_partial_json(input)?).ok()
+}
+
+struct Invalid;
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn at_end(&self) -> bool {
+        self.position >= self.chars.len()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += 1;
+        Some(c)
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(c) if c.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    // `Ok(None)` means the input ended before anything usable was read
+    fn parse_value(&mut self) -> Result<Option<Value>, Invalid> {
+        self.skip_whitespace();
+        match self.peek() {
+            None => Ok(None),
+            Some('{') => self.nested(Self::parse_object).map(Some),
+            Some('[') => self.nested(Self::parse_array).map(Some),
+            Some('"') => self.parse_string().map(|s| Some(Value::String(s))),
+            Some('t') => self.parse_literal("true", Value::Bool(true)),
+            Some('f') => self.parse_literal("false", Value::Bool(false)),
+            Some('n') => self.parse_literal("null", Value::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(_) => Err(Invalid),
+        }
+    }
+
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Value, Invalid>,
+    ) -> Result<Value, Invalid> {
+        if self.depth >= MAX_DEPTH {
+            return Err(Invalid);
+        }
+
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    fn parse_object(&mut self) -> Result<Value, Invalid> {
+        self.next();
+        let mut object = Map::new();
+
+        loop {
+            self.skip_whitespace();
+            match self.next() {
+                None => break,
+                Some('}') => break,
+                Some('"') => self.position -= 1,
+                Some(_) => return Err(Invalid),
+            }
+
+            let key = self.parse_string()?;
+            if self.at_end() {
+                break;
+            }
+
+            self.skip_whitespace();
+            match self.next() {
+                None => break,
+                Some(':') => {}
+                Some(_) => return Err(Invalid),
+            }
+
+            match self.parse_value()? {
+                Some(value) => object.insert(key, value),
+                None => break,
+            };
+
+            self.skip_whitespace();
+            match self.next() {
+                None | Some('}') => break,
+                Some(',') => {}
+                Some(_) => return Err(Invalid),
+            }
+        }
+
+        Ok(Value::Object(object))
+    }
+
+    fn parse_array(&mut self) -> Result<Value, Invalid> {
+        self.next();
+        let mut array = vec![];
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some(']') {
+                self.next();
+                break;
+            }
+
+            match self.parse_value()? {
+                Some(value) => array.push(value),
+                None => break,
+            }
+
+            self.skip_whitespace();
+            match self.next() {
+                None | Some(']') => break,
+                Some(',') => {}
+                Some(_) => return Err(Invalid),
+            }
+        }
+
+        Ok(Value::Array(array))
+    }
+
+    // a string that is cut off is returned as far as it goes
+    fn parse_string(&mut self) -> Result<String, Invalid> {
+        self.next();
+        let mut s = String::new();
+
+        while let Some(c) = self.next() {
+            match c {
+                '"' => return Ok(s),
+                '\\' => match self.next() {
+                    None => break,
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => match self.parse_unicode_escape()? {
+                        Some(c) => s.push(c),
+                        None => break,
+                    },
+                    Some(_) => return Err(Invalid),
+                },
+                c => s.push(c),
+            }
+        }
+
+        // the input ended inside the string
+        self.position = self.chars.len();
+        Ok(s)
+    }
+
+    fn parse_unicode_escape(&mut self) -> Result<Option<char>, Invalid> {
+        let Some(high) = self.parse_hex()? else {
+            return Ok(None);
+        };
+
+        if !(0xD800..0xDC00).contains(&high) {
+            return Ok(Some(
+                char::from_u32(high).unwrap_or(char::REPLACEMENT_CHARACTER),
+            ));
+        }
+
+        // surrogate pair, the low half follows as another escape
+        match (self.next(), self.next()) {
+            (Some('\\'), Some('u')) => {}
+            (None, _) | (Some('\\'), None) => return Ok(None),
+            _ => return Err(Invalid),
+        }
+        let Some(low) = self.parse_hex()? else {
+            return Ok(None);
+        };
+        let c = 0x10000 + ((high - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+        Ok(Some(
+            char::from_u32(c).unwrap_or(char::REPLACEMENT_CHARACTER),
+        ))
+    }
+
+    fn parse_hex(&mut self) -> Result<Option<u32>, Invalid> {
+        let mut n = 0;
+        for _ in 0..4 {
+            match self.next() {
+                None => return Ok(None),
+                Some(c) => n = n * 16 + c.to_digit(16).ok_or(Invalid)?,
+            }
+        }
+        Ok(Some(n))
+    }
+
+    // `tr` at the end of the input can only become `true`
+    fn parse_literal(&mut self, literal: &str, value: Value) -> Result<Option<Value>, Invalid> {
+        for expected in literal.chars() {
+            match self.next() {
+                None => return Ok(Some(value)),
+                Some(c) if c == expected => {}
+                Some(_) => return Err(Invalid),
+            }
+        }
+        Ok(Some(value))
+    }
+
+    fn parse_number(&mut self) -> Result<Option<Value>, Invalid> {
+        let start = self.position;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit() || "+-.eE".contains(c)) {
+            self.position += 1;
+        }
+
+        let text: String = self.chars[start..self.position].iter().collect();
+        if let Ok(number) = text.parse::<Number>() {
+            return Ok(Some(Value::Number(number)));
+        }
+        if !self.at_end() {
+            return Err(Invalid);
+        }
+
+        // cut off in the middle, like `-`, `1.` or `1e+`, fall back to the longest valid prefix
+        let prefix = text.trim_end_matches(|c: char| !c.is_ascii_digit());
+        match prefix.parse::<Number>() {
+            Ok(number) => Ok(Some(Value::Number(number))),
+            Err(_) if text.chars().all(|c| c == '-') => Ok(None),
+            Err(_) => Err(Invalid),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn completes_truncated_strings() {
+        assert_eq!(parse_partial_json(r#""hel"#), Some(json!("hel")));
+        assert_eq!(
+            parse_partial_json(r#"{"city": "Par"#),
+            Some(json!({ "city": "Par" }))
+        );
+        // a key without a value is left out
+        assert_eq!(
+            parse_partial_json(r#"{"city": "Paris", "coun"#),
+            Some(json!({ "city": "Paris" }))
+        );
+        assert_eq!(
+            parse_partial_json(r#"{"city": "Paris", "country":"#),
+            Some(json!({ "city": "Paris" }))
+        );
+    }
+
+    #[test]
+    fn handles_escapes() {
+        assert_eq!(
+            parse_partial_json(r#""a\"b\\c\nd""#),
+            Some(json!("a\"b\\c\nd"))
+        );
+        assert_eq!(parse_partial_json(r#""\u00e9""#), Some(json!("é")));
+        assert_eq!(parse_partial_json(r#""\ud83d\ude00""#), Some(json!("😀")));
+
+        // cut off inside an escape, the escape is dropped
+        assert_eq!(parse_partial_json(r#""ab\"#), Some(json!("ab")));
+        assert_eq!(parse_partial_json(r#""ab\u00"#), Some(json!("ab")));
+        assert_eq!(parse_partial_json(r#""ab\ud83d\u"#), Some(json!("ab")));
+
+        assert_eq!(parse_partial_json(r#""\x""#), None);
+        assert_eq!(parse_partial_json(r#""\u00zz""#), None);
+    }
+
+    #[test]
+    fn completes_numbers() {
+        assert_eq!(parse_partial_json("12"), Some(json!(12)));
+        assert_eq!(parse_partial_json("-3.25"), Some(json!(-3.25)));
+        assert_eq!(parse_partial_json("1."), Some(json!(1)));
+        assert_eq!(parse_partial_json("1.5e"), Some(json!(1.5)));
+        assert_eq!(parse_partial_json("2e+"), Some(json!(2)));
+        assert_eq!(parse_partial_json("[1, -"), Some(json!([1])));
+        assert_eq!(parse_partial_json("-"), None);
+        assert_eq!(parse_partial_json("[1.2.3]"), None);
+    }
+
+    #[test]
+    fn completes_literals() {
+        assert_eq!(parse_partial_json("tr"), Some(json!(true)));
+        assert_eq!(parse_partial_json("[fals"), Some(json!([false])));
+        assert_eq!(parse_partial_json("{\"a\": n"), Some(json!({ "a": null })));
+        assert_eq!(parse_partial_json("tx"), None);
+    }
+
+    #[test]
+    fn closes_nested_containers() {
+        assert_eq!(
+            parse_partial_json(r#"{"items": [{"id": 1}, {"id": 2, "tags": ["a", "b"#),
+            Some(json!({ "items": [{ "id": 1 }, { "id": 2, "tags": ["a", "b"] }] }))
+        );
+        assert_eq!(parse_partial_json("[[["), Some(json!([[[]]])));
+        assert_eq!(parse_partial_json(r#"{"a": {"#), Some(json!({ "a": {} })));
+    }
+
+    #[test]
+    fn every_prefix_parses() {
+        let input = r#"{"name": "Ada \"Countess\" Lovelace", "born": 1815, "tags": ["math", null, true], "ratio": -1.5e3}"#;
+        for (end, _) in input.char_indices().skip(1) {
+            assert!(
+                parse_partial_json(&input[..end]).is_some(),
+                "prefix {:?}",
+                &input[..end]
+            );
+        }
+        assert_eq!(
+            parse_partial_json(input),
+            Some(serde_json::from_str(input).unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(parse_partial_json(""), None);
+        assert_eq!(parse_partial_json("   "), None);
+        assert_eq!(parse_partial_json("{\"a\" 1}"), None);
+        assert_eq!(parse_partial_json("[1] x"), None);
+        assert_eq!(parse_partial_json("{1: 2}"), None);
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let deep = "[".repeat(100_000);
+        assert_eq!(parse_partial_json(&deep), None);
+
+        let shallow = "[".repeat(MAX_DEPTH);
+        assert!(parse_partial_json(&shallow).is_some());
+        assert_eq!(parse_partial_json(&"[".repeat(MAX_DEPTH + 1)), None);
+    }
+
+    #[test]
+    fn deserializes_partial_values() {
+        #[derive(serde_derive::Deserialize, Debug, PartialEq)]
+        struct Args {
+            city: Option<String>,
+            #[serde(default)]
+            days: Vec<u32>,
+        }
+
+        assert_eq!(
+            parse_partial::<Args>(r#"{"city": "Ber"#),
+            Some(Args {
+                city: Some("Ber".to_string()),
+                days: vec![]
+            })
+        );
+        assert_eq!(
+            parse_partial::<Args>(r#"{"city": "Berlin", "days": [1, 2"#),
+            Some(Args {
+                city: Some("Berlin".to_string()),
+                days: vec![1, 2]
+            })
+        );
+    }
+}
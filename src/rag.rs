@@ -0,0 +1,605 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::error::{InternalError, UtilsResult};
+use crate::{
+    calculate_tokens, AiAgent, Client, Document, Embeddings, Filter, Message, VectorStore,
+    DEFAULT_CLIENT,
+};
+
+// metadata keys of the chunks in the store
+const SOURCE_ID: &str = "source_id";
+const CHUNK_INDEX: &str = "chunk_index";
+
+// name of the context message, so the next `augment` can find and replace it
+const CONTEXT_NAME: &str = "retrieved_context";
+
+const CONTEXT_HEADER: &str =
+    "Answer using the following context. Cite the passages you use by their number, e.g. [1].\n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContextRole {
+    // an additional system message, the agent's own system message stays as it is
+    #[default]
+    System,
+    User,
+}
+
+// A retrieved chunk. The context refers to it as `[number]`, so answers citing it can be
+// mapped back to the document it came from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Citation {
+    pub number: usize,
+    pub source_id: String,
+    pub chunk_index: usize,
+    pub text: String,
+    pub score: f32,
+}
+
+// Splits documents into chunks, embeds them and adds the chunks most relevant to a query to
+// the context of an agent.
+#[derive(Debug, Clone)]
+pub struct Rag {
+    pub embeddings: Embeddings,
+    pub store: VectorStore,
+
+    // size of the chunks and how much consecutive chunks share, in tokens
+    pub chunk_tokens: usize,
+    pub chunk_overlap: usize,
+
+    pub top_k: usize,
+
+    // the most tokens the context message may take up, its framing included
+    pub token_budget: usize,
+    pub context_role: ContextRole,
+}
+
+impl Rag {
+    pub fn new(embeddings: Embeddings) -> Self {
+        Self {
+            embeddings,
+            store: VectorStore::new(),
+            chunk_tokens: 512,
+            chunk_overlap: 64,
+            top_k: 5,
+            token_budget: 2000,
+            context_role: ContextRole::System,
+        }
+    }
+
+    pub fn with_store(mut self, store: VectorStore) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn with_chunk_tokens(mut self, chunk_tokens: usize) -> Self {
+        self.chunk_tokens = chunk_tokens.max(1);
+        self
+    }
+
+    pub fn with_chunk_overlap(mut self, chunk_overlap: usize) -> Self {
+        self.chunk_overlap = chunk_overlap;
+        self
+    }
+
+    pub fn with_top_k(mut self, top_k: usize) -> Self {
+        self.top_k = top_k;
+        self
+    }
+
+    pub fn with_token_budget(mut self, token_budget: usize) -> Self {
+        self.token_budget = token_budget;
+        self
+    }
+
+    pub fn with_context_role(mut self, context_role: ContextRole) -> Self {
+        self.context_role = context_role;
+        self
+    }
+
+    // splits text into chunks of at most `chunk_tokens` tokens, breaking between words where
+    // possible. consecutive chunks share up to `chunk_overlap` tokens.
+    pub fn chunk(&self, text: &str) -> Vec<String> {
+        let offsets = token_offsets(text);
+        let tokens = offsets.len() - 1;
+
+        // token positions a chunk can start or end at without splitting a character, and
+        // whether they also don't split a word
+        let splits: Vec<(usize, bool)> = (0..=tokens)
+            .filter_map(|i| {
+                let offset = offsets[i]?;
+                let word = i == tokens || text[offset..].starts_with(char::is_whitespace);
+                Some((i, word))
+            })
+            .collect();
+        // chunks only ever start and end at one of the splits
+        let at = |i: usize| offsets[i].expect("chunk boundary inside a character");
+
+        let mut chunks = vec![];
+        let mut start = 0;
+        while start < tokens {
+            let end = match start + self.chunk_tokens {
+                limit if limit >= tokens => tokens,
+                limit => {
+                    let first = splits.partition_point(|&(i, _)| i <= start);
+                    let within = &splits[first..splits.partition_point(|&(i, _)| i <= limit)];
+                    // the last word boundary in reach, else the last character boundary. a
+                    // single character longer than a chunk still makes up a chunk of its own.
+                    within
+                        .iter()
+                        .rev()
+                        .find(|&&(_, word)| word)
+                        .or(within.last())
+                        .or(splits.get(first))
+                        .map_or(tokens, |&(i, _)| i)
+                }
+            };
+
+            let chunk = text[at(start)..at(end)].trim();
+            if !chunk.is_empty() {
+                chunks.push(chunk.to_string());
+            }
+            if end == tokens {
+                break;
+            }
+
+            // step back for the overlap, but always make progress
+            let from = end.saturating_sub(self.chunk_overlap).max(start + 1);
+            let overlap = &splits[splits.partition_point(|&(i, _)| i < from)
+                ..splits.partition_point(|&(i, _)| i <= end)];
+            start = overlap
+                .iter()
+                .find(|&&(_, word)| word)
+                .or(overlap.first())
+                .map_or(end, |&(i, _)| i);
+        }
+
+        chunks
+    }
+
+    pub async fn add_document(&mut self, source_id: &str, text: &str) -> UtilsResult<usize> {
+        self.add_documents_with(
+            &DEFAULT_CLIENT,
+            vec![(source_id.to_string(), text.to_string())],
+        )
+        .await
+    }
+
+    // chunks and embeds `(source id, text)` pairs, replacing earlier chunks of the same source.
+    // nothing in the store changes unless embedding succeeds. returns the number of chunks added.
+    pub async fn add_documents_with(
+        &mut self,
+        client: &Client,
+        documents: Vec<(String, String)>,
+    ) -> UtilsResult<usize> {
+        let mut sources = vec![];
+        let mut chunks = vec![];
+        for (source_id, text) in documents {
+            for (i, chunk) in self.chunk(&text).into_iter().enumerate() {
+                chunks.push(
+                    Document::new(format!("{}#{}", source_id, i), chunk)
+                        .with_metadata(SOURCE_ID, source_id.clone())
+                        .with_metadata(CHUNK_INDEX, i),
+                );
+            }
+            sources.push(source_id);
+        }
+
+        if !chunks.is_empty() {
+            let texts: Vec<String> = chunks.iter().map(|chunk| chunk.text.clone()).collect();
+            let vectors = self.embeddings.create_with(client, texts).await?.vectors();
+            if vectors.len() != chunks.len() {
+                Err(InternalError::NoEmbeddings)?
+            }
+            for (chunk, vector) in chunks.iter_mut().zip(vectors) {
+                chunk.embedding = vector;
+            }
+        }
+
+        // checked up front, so a mismatch doesn't leave a source half replaced
+        if let Some(expected) = self.store.dimensions() {
+            if let Some(chunk) = chunks
+                .iter()
+                .find(|chunk| chunk.embedding.len() != expected)
+            {
+                Err(InternalError::DimensionMismatch(
+                    expected,
+                    chunk.embedding.len(),
+                ))?
+            }
+        }
+
+        for source_id in sources {
+            self.store.remove_where(&Filter::eq(SOURCE_ID, source_id));
+        }
+
+        let count = chunks.len();
+        for chunk in chunks {
+            self.store.add(chunk)?;
+        }
+        Ok(count)
+    }
+
+    // the most relevant chunks for the query, as many as fit into the token budget
+    pub async fn retrieve_with(&self, client: &Client, query: &str) -> UtilsResult<Vec<Citation>> {
+        let results = self
+            .store
+            .query(&self.embeddings, client, query, self.top_k, None)
+            .await?;
+
+        let mut citations = vec![];
+        let mut tokens = calculate_tokens(CONTEXT_HEADER);
+        for result in results {
+            let text = result.document.text.clone();
+            let cost = calculate_tokens(&passage(citations.len() + 1, &text));
+            // a smaller chunk further down may still fit
+            if tokens + cost > self.token_budget {
+                continue;
+            }
+            tokens += cost;
+
+            let metadata = &result.document.metadata;
+            citations.push(Citation {
+                number: citations.len() + 1,
+                source_id: metadata
+                    .get(SOURCE_ID)
+                    .and_then(|id| id.as_str())
+                    .unwrap_or(&result.document.id)
+                    .to_string(),
+                chunk_index: metadata
+                    .get(CHUNK_INDEX)
+                    .and_then(|i| i.as_u64())
+                    .unwrap_or_default() as usize,
+                text,
+                score: result.score,
+            });
+        }
+
+        Ok(citations)
+    }
+
+    pub fn context_message(&self, citations: &[Citation]) -> Message {
+        let mut content = String::from(CONTEXT_HEADER);
+        for citation in citations {
+            content.push_str(&passage(citation.number, &citation.text));
+        }
+
+        let role = match self.context_role {
+            ContextRole::System => "system",
+            ContextRole::User => "user",
+        };
+        Message::new(role)
+            .with_name(CONTEXT_NAME)
+            .with_content(content)
+    }
+
+    pub async fn augment(&self, agent: &mut AiAgent, query: &str) -> UtilsResult<Vec<Citation>> {
+        self.augment_with(&DEFAULT_CLIENT, agent, query).await
+    }
+
+    // retrieves the chunks relevant to the query and adds them to the messages of the agent,
+    // replacing the context of an earlier question. ask the question itself afterwards.
+    pub async fn augment_with(
+        &self,
+        client: &Client,
+        agent: &mut AiAgent,
+        query: &str,
+    ) -> UtilsResult<Vec<Citation>> {
+        let citations = self.retrieve_with(client, query).await?;
+        agent
+            .messages
+            .retain(|message| message.name.as_deref() != Some(CONTEXT_NAME));
+        if citations.is_empty() {
+            return Ok(citations);
+        }
+
+        let message = self.context_message(&citations);
+        match self.context_role {
+            // right after the agent's own system messages, not in the middle of the conversation
+            ContextRole::System => {
+                let position = agent
+                    .messages
+                    .iter()
+                    .take_while(|message| message.role == "system")
+                    .count();
+                agent.messages.insert(position, message);
+            }
+            ContextRole::User => agent.push_message(message),
+        }
+        Ok(citations)
+    }
+}
+
+fn passage(number: usize, text: &str) -> String {
+    format!("\n[{}]\n{}\n", number, text)
+}
+
+// byte offset at which every token of the text starts, followed by the end of the text. tokens
+// starting in the middle of a character, which the tokenizer may split, have none. the text is
+// tokenized once, the tokenizer is shared and locked for the whole time.
+fn token_offsets(text: &str) -> Vec<Option<usize>> {
+    let bpe = tiktoken_rs::cl100k_base_singleton();
+    let bpe = bpe.lock();
+
+    let mut offsets = vec![];
+    let mut offset = 0;
+    let mut pending = vec![];
+    for token in bpe.encode_with_special_tokens(text) {
+        offsets.push(pending.is_empty().then_some(offset));
+        pending.push(token);
+        // a token ending inside a character only decodes together with the ones completing it
+        if let Ok(decoded) = bpe.decode(pending.clone()) {
+            offset += decoded.len();
+            pending.clear();
+        }
+    }
+    offsets.push(Some(text.len()));
+    offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Reply, TestServer};
+    use crate::RetryPolicy;
+    use serde_json::json;
+
+    const TEXT: &str = "one two three four five six seven eight nine ten eleven twelve thirteen \
+        fourteen fifteen sixteen seventeen eighteen nineteen twenty\n\n  end";
+
+    fn rag(chunk_tokens: usize, chunk_overlap: usize) -> Rag {
+        Rag::new(Embeddings::new("text-embedding-3-small"))
+            .with_chunk_tokens(chunk_tokens)
+            .with_chunk_overlap(chunk_overlap)
+    }
+
+    // the words in the text have a space in front, which the tokenizer merges into the word
+    fn tokens_in_text(words: &str) -> usize {
+        calculate_tokens(&format!(" {}", words))
+    }
+
+    fn words(chunks: &[String]) -> Vec<Vec<&str>> {
+        chunks
+            .iter()
+            .map(|chunk| chunk.split_whitespace().collect())
+            .collect()
+    }
+
+    #[test]
+    fn chunks_stay_within_the_token_limit() {
+        let chunks = rag(10, 3).chunk(TEXT);
+
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(tokens_in_text(chunk) <= 10, "{:?}", chunk);
+        }
+        assert_eq!(
+            chunks.first().unwrap().split_whitespace().next(),
+            Some("one")
+        );
+        assert_eq!(
+            chunks.last().unwrap().split_whitespace().last(),
+            Some("end")
+        );
+    }
+
+    #[test]
+    fn consecutive_chunks_overlap() {
+        let chunks = rag(10, 3).chunk(TEXT);
+        let words = words(&chunks);
+
+        for pair in words.windows(2) {
+            let (previous, next) = (&pair[0], &pair[1]);
+            // the next chunk starts with the last words of the previous one
+            let shared = (1..=previous.len().min(next.len()))
+                .rev()
+                .find(|&n| previous[previous.len() - n..] == next[..n])
+                .unwrap_or(0);
+            assert!(shared > 0, "{:?} and {:?} don't overlap", previous, next);
+            assert!(tokens_in_text(&next[..shared].join(" ")) <= 3);
+        }
+    }
+
+    #[test]
+    fn chunks_without_overlap_cover_the_text_once() {
+        let chunks = rag(10, 0).chunk(TEXT);
+        let words: Vec<&str> = words(&chunks).into_iter().flatten().collect();
+        assert_eq!(words, TEXT.split_whitespace().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn always_makes_progress() {
+        // an overlap as large as the chunk would otherwise start every chunk at the same place
+        let chunks = rag(4, 10).chunk(TEXT);
+        assert!(chunks.len() < 100);
+        assert_eq!(
+            chunks.last().unwrap().split_whitespace().last(),
+            Some("end")
+        );
+
+        let chunks = rag(1, 1).chunk("a b c");
+        assert_eq!(chunks, vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn long_words_and_multibyte_text_are_split_on_character_boundaries() {
+        let word = "Donaudampfschifffahrtsgesellschaftskapitän".repeat(3);
+        let chunks = rag(4, 0).chunk(&word);
+        assert!(chunks.len() > 1);
+        assert_eq!(chunks.concat(), word);
+
+        let emoji = "😀🎉🚀 ✨🌍".repeat(5);
+        let chunks = rag(3, 0).chunk(&emoji);
+        assert_eq!(
+            chunks.join(" ").split_whitespace().collect::<String>(),
+            emoji.split_whitespace().collect::<String>()
+        );
+    }
+
+    #[test]
+    fn token_offsets_skip_tokens_inside_characters() {
+        let text = "hi 😀🎉🚀 kapitän";
+        let offsets = token_offsets(text);
+        assert_eq!(offsets.len(), calculate_tokens(text) + 1);
+        assert_eq!(offsets[0], Some(0));
+        assert_eq!(offsets.last(), Some(&Some(text.len())));
+
+        // emoji take several tokens each, only the first of them starts at a character
+        assert!(offsets.iter().any(Option::is_none));
+        let known: Vec<usize> = offsets.iter().flatten().copied().collect();
+        assert!(known.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(known.iter().all(|&offset| text.is_char_boundary(offset)));
+    }
+
+    #[test]
+    fn empty_text_has_no_chunks() {
+        assert!(rag(10, 3).chunk("").is_empty());
+        assert!(rag(10, 3).chunk(" \n\t ").is_empty());
+    }
+
+    #[tokio::test]
+    async fn failed_embeddings_keep_the_old_chunks() {
+        let mut rag = rag(10, 0);
+        let old = Document::new("doc#0", "old text")
+            .with_metadata(SOURCE_ID, "doc")
+            .with_metadata(CHUNK_INDEX, 0)
+            .with_embedding(vec![1.0, 0.0]);
+        rag.store.add(old).unwrap();
+
+        // nothing listens there
+        let client = Client::new()
+            .with_base_url("http://127.0.0.1:1")
+            .with_retry_policy(RetryPolicy::none());
+        let result = rag
+            .add_documents_with(&client, vec![("doc".to_string(), TEXT.to_string())])
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(rag.store.len(), 1);
+        assert_eq!(rag.store.get("doc#0").unwrap().text, "old text");
+    }
+
+    fn query_embedding(vector: [f32; 2]) -> Reply {
+        Reply::json(
+            200,
+            &json!({
+                "object": "list",
+                "data": [{"object": "embedding", "index": 0, "embedding": vector}],
+                "model": "text-embedding-3-small",
+                "usage": {"prompt_tokens": 2, "total_tokens": 2},
+            }),
+        )
+    }
+
+    fn indexed_rag() -> Rag {
+        let mut rag = rag(10, 0).with_top_k(1);
+        let chunks = [
+            ("apples", "apples are red", [1.0, 0.0]),
+            ("pears", "pears are green", [0.0, 1.0]),
+        ];
+        for (source, text, embedding) in chunks {
+            let chunk = Document::new(format!("{}#0", source), text)
+                .with_metadata(SOURCE_ID, source)
+                .with_metadata(CHUNK_INDEX, 0)
+                .with_embedding(embedding.to_vec());
+            rag.store.add(chunk).unwrap();
+        }
+        rag
+    }
+
+    fn contents(agent: &AiAgent) -> Vec<(String, String)> {
+        agent
+            .messages
+            .iter()
+            .map(|m| (m.role.clone(), m.content.as_ref().unwrap().text()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn system_context_is_replaced_next_to_the_system_message() {
+        let server = TestServer::start(vec![
+            query_embedding([1.0, 0.0]),
+            query_embedding([0.0, 1.0]),
+        ])
+        .await;
+        let rag = indexed_rag();
+        let mut agent = AiAgent::new("gpt-4o").with_system_message("be brief");
+
+        rag.augment_with(&server.client(), &mut agent, "apples?")
+            .await
+            .unwrap();
+        agent.push_message(Message::new("user").with_content("what color are apples?"));
+        agent.push_message(Message::new("assistant").with_content("red [1]"));
+
+        rag.augment_with(&server.client(), &mut agent, "pears?")
+            .await
+            .unwrap();
+        agent.push_message(Message::new("user").with_content("and pears?"));
+
+        let messages = contents(&agent);
+        let roles: Vec<&str> = messages.iter().map(|(role, _)| role.as_str()).collect();
+        assert_eq!(roles, vec!["system", "user", "assistant", "user"]);
+        assert!(messages[0].1.contains("pears are green"));
+        assert!(!messages
+            .iter()
+            .any(|(_, content)| content.contains("apples are red")));
+
+        // the agent's own system message still comes first
+        let request = agent.build_request(false);
+        assert_eq!(
+            request.messages[0].content.as_ref().unwrap().text(),
+            "be brief"
+        );
+        assert_eq!(request.messages[1].name.as_deref(), Some(CONTEXT_NAME));
+    }
+
+    #[tokio::test]
+    async fn user_context_is_replaced_at_the_end() {
+        let server = TestServer::start(vec![
+            query_embedding([1.0, 0.0]),
+            query_embedding([0.0, 1.0]),
+        ])
+        .await;
+        let rag = indexed_rag().with_context_role(ContextRole::User);
+        let mut agent = AiAgent::new("gpt-4o");
+
+        rag.augment_with(&server.client(), &mut agent, "apples?")
+            .await
+            .unwrap();
+        agent.push_message(Message::new("user").with_content("what color are apples?"));
+        rag.augment_with(&server.client(), &mut agent, "pears?")
+            .await
+            .unwrap();
+
+        let messages = contents(&agent);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].1, "what color are apples?");
+        assert!(messages[1].1.contains("pears are green"));
+    }
+
+    #[tokio::test]
+    async fn budget_includes_the_framing() {
+        let framing =
+            calculate_tokens(CONTEXT_HEADER) + calculate_tokens(&passage(1, "apples are red"));
+
+        let server = TestServer::start(vec![
+            query_embedding([1.0, 0.0]),
+            query_embedding([1.0, 0.0]),
+        ])
+        .await;
+        let rag = indexed_rag().with_token_budget(framing - 1);
+        let citations = rag
+            .retrieve_with(&server.client(), "apples?")
+            .await
+            .unwrap();
+        assert!(citations.is_empty());
+
+        let rag = rag.with_token_budget(framing);
+        let citations = rag
+            .retrieve_with(&server.client(), "apples?")
+            .await
+            .unwrap();
+        assert_eq!(citations.len(), 1);
+        assert_eq!(citations[0].source_id, "apples");
+    }
+}
@@ -0,0 +1,290 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use reqwest::header::HeaderMap;
+
+use crate::retry::parse_reset_duration;
+
+// Client side token buckets for requests and tokens per minute. Clones share the same
+// buckets, so one limiter can be handed to every client that draws from the same org limits.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    inner: Arc<Mutex<Buckets>>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn per_minute(capacity: u64) -> Self {
+        Self {
+            capacity: capacity as f64,
+            available: capacity as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    // how long until `amount` is available, requests larger than the bucket only wait for a full bucket
+    fn wait_time(&self, amount: f64) -> Duration {
+        let missing = amount.min(self.capacity) - self.available;
+        if missing <= 0.0 || self.capacity <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing * 60.0 / self.capacity)
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.available -= amount.min(self.capacity);
+    }
+
+    fn update(&mut self, limit: Option<u64>, remaining: Option<u64>, reset: Option<Duration>) {
+        if let Some(limit) = limit {
+            self.capacity = limit as f64;
+        }
+
+        if let Some(remaining) = remaining {
+            // the server doesn't know about requests we already let through, so never raise our estimate
+            self.available = self.available.min(remaining as f64);
+        }
+
+        // if the server says the bucket is empty until the reset, believe it over the linear refill
+        if let (Some(0), Some(reset)) = (remaining, reset) {
+            self.available = -(reset.as_secs_f64() * self.capacity / 60.0);
+        }
+
+        self.available = self.available.min(self.capacity);
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // builder part
+
+    pub fn with_requests_per_minute(self, requests_per_minute: u64) -> Self {
+        self.inner.lock().expect("failed to get lock").requests =
+            Some(Bucket::per_minute(requests_per_minute));
+        self
+    }
+
+    pub fn with_tokens_per_minute(self, tokens_per_minute: u64) -> Self {
+        self.inner.lock().expect("failed to get lock").tokens =
+            Some(Bucket::per_minute(tokens_per_minute));
+        self
+    }
+
+    // limiter part
+
+    // waits until one request using `tokens` tokens fits into both buckets, then takes it
+    pub async fn acquire(&self, tokens: u64) {
+        loop {
+            let wait = {
+                let mut buckets = self.inner.lock().expect("failed to get lock");
+                let now = Instant::now();
+                let tokens = tokens as f64;
+
+                let Buckets {
+                    requests: request_bucket,
+                    tokens: token_bucket,
+                } = &mut *buckets;
+                request_bucket.iter_mut().for_each(|b| b.refill(now));
+                token_bucket.iter_mut().for_each(|b| b.refill(now));
+
+                let wait = request_bucket
+                    .as_ref()
+                    .map(|b| b.wait_time(1.0))
+                    .unwrap_or_default()
+                    .max(
+                        token_bucket
+                            .as_ref()
+                            .map(|b| b.wait_time(tokens))
+                            .unwrap_or_default(),
+                    );
+
+                if wait.is_zero() {
+                    request_bucket.iter_mut().for_each(|b| b.take(1.0));
+                    token_bucket.iter_mut().for_each(|b| b.take(tokens));
+                    return;
+                }
+
+                wait
+            };
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // syncs the buckets with the `x-ratelimit-*` headers of a response, creating them if
+    // the limiter was not configured with explicit limits
+    pub fn update_from_headers(&self, headers: &HeaderMap) {
+        let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+        let number = |name: &str| header(name).and_then(|v| v.trim().parse::<u64>().ok());
+        let duration = |name: &str| header(name).and_then(parse_reset_duration);
+
+        let mut buckets = self.inner.lock().expect("failed to get lock");
+        let Buckets { requests, tokens } = &mut *buckets;
+        let now = Instant::now();
+
+        for (bucket, kind) in [(requests, "requests"), (tokens, "tokens")] {
+            let limit = number(&format!("x-ratelimit-limit-{kind}"));
+            let remaining = number(&format!("x-ratelimit-remaining-{kind}"));
+            let reset = duration(&format!("x-ratelimit-reset-{kind}"));
+
+            if bucket.is_none() {
+                match limit {
+                    Some(limit) => *bucket = Some(Bucket::per_minute(limit)),
+                    None => continue,
+                }
+            }
+
+            if let Some(bucket) = bucket {
+                bucket.refill(now);
+                bucket.update(limit, remaining, reset);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn refills_linearly_up_to_capacity() {
+        let mut bucket = Bucket::per_minute(60);
+        let start = bucket.last_refill;
+        bucket.take(60.0);
+        assert_eq!(bucket.available, 0.0);
+
+        bucket.refill(start + Duration::from_secs(10));
+        assert!((bucket.available - 10.0).abs() < 1e-9);
+
+        // never more than a full bucket, however long it was idle
+        bucket.refill(start + Duration::from_secs(600));
+        assert_eq!(bucket.available, 60.0);
+    }
+
+    #[test]
+    fn waits_for_missing_amount() {
+        let mut bucket = Bucket::per_minute(60);
+        assert_eq!(bucket.wait_time(1.0), Duration::ZERO);
+
+        bucket.take(60.0);
+        assert_eq!(bucket.wait_time(1.0), Duration::from_secs(1));
+        assert_eq!(bucket.wait_time(30.0), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn oversized_requests_wait_for_a_full_bucket() {
+        let mut bucket = Bucket::per_minute(60);
+        assert_eq!(bucket.wait_time(1000.0), Duration::ZERO);
+
+        bucket.take(1000.0);
+        assert_eq!(bucket.available, 0.0);
+        assert_eq!(bucket.wait_time(1000.0), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn empty_bucket_with_reset_goes_negative() {
+        let mut bucket = Bucket::per_minute(60);
+        bucket.update(None, Some(0), Some(Duration::from_secs(30)));
+
+        assert_eq!(bucket.available, -30.0);
+        assert_eq!(bucket.wait_time(1.0), Duration::from_secs(31));
+    }
+
+    #[test]
+    fn update_never_raises_the_estimate() {
+        let mut bucket = Bucket::per_minute(100);
+        bucket.take(90.0);
+        bucket.update(Some(100), Some(50), None);
+        assert_eq!(bucket.available, 10.0);
+
+        bucket.update(Some(200), Some(5), None);
+        assert_eq!(bucket.capacity, 200.0);
+        assert_eq!(bucket.available, 5.0);
+    }
+
+    #[test]
+    fn headers_create_missing_buckets() {
+        let limiter = RateLimiter::new();
+        limiter.update_from_headers(&headers(&[
+            ("x-ratelimit-limit-requests", "500"),
+            ("x-ratelimit-remaining-requests", "499"),
+            ("x-ratelimit-limit-tokens", "30000"),
+            ("x-ratelimit-remaining-tokens", "29000"),
+            ("x-ratelimit-reset-tokens", "2s"),
+        ]));
+
+        let buckets = limiter.inner.lock().unwrap();
+        let requests = buckets.requests.as_ref().unwrap();
+        assert_eq!(requests.capacity, 500.0);
+        assert_eq!(requests.available, 499.0);
+
+        let tokens = buckets.tokens.as_ref().unwrap();
+        assert_eq!(tokens.capacity, 30000.0);
+        assert_eq!(tokens.available, 29000.0);
+    }
+
+    #[test]
+    fn headers_without_limits_create_nothing() {
+        let limiter = RateLimiter::new();
+        limiter.update_from_headers(&headers(&[("x-ratelimit-remaining-requests", "10")]));
+
+        let buckets = limiter.inner.lock().unwrap();
+        assert!(buckets.requests.is_none());
+        assert!(buckets.tokens.is_none());
+    }
+
+    #[tokio::test]
+    async fn acquire_without_limits_does_not_block() {
+        let limiter = RateLimiter::new();
+        let acquired = tokio::time::timeout(Duration::from_millis(100), async {
+            for _ in 0..1000 {
+                limiter.acquire(1_000_000).await;
+            }
+        })
+        .await;
+        assert!(acquired.is_ok());
+    }
+
+    #[tokio::test]
+    async fn acquire_takes_from_both_buckets() {
+        let limiter = RateLimiter::new()
+            .with_requests_per_minute(10)
+            .with_tokens_per_minute(1000);
+        limiter.acquire(400).await;
+
+        let buckets = limiter.inner.lock().unwrap();
+        assert!((buckets.requests.as_ref().unwrap().available - 9.0).abs() < 1e-3);
+        assert!((buckets.tokens.as_ref().unwrap().available - 600.0).abs() < 1e-1);
+    }
+}
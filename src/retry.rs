@@ -0,0 +1,297 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+
+// never wait longer than this on a single retry, no matter what the server asks for
+const MAX_SERVER_DELAY: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    // total number of attempts, including the first one
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub multiplier: f64,
+
+    // fraction of the backoff that is randomized, 0.0 disables jitter
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(8),
+            multiplier: 2.0,
+            jitter: 0.25,
+        }
+    }
+
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::new()
+        }
+    }
+
+    // builder part
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    pub fn with_initial_backoff(mut self, initial_backoff: Duration) -> Self {
+        self.initial_backoff = initial_backoff;
+        self
+    }
+
+    pub fn with_max_backoff(mut self, max_backoff: Duration) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    // policy part
+
+    pub fn can_retry(&self, attempt: u32) -> bool {
+        attempt < self.max_attempts
+    }
+
+    pub fn is_retryable_status(status: StatusCode) -> bool {
+        status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::REQUEST_TIMEOUT
+            || status.is_server_error()
+    }
+
+    pub fn is_retryable_transport(e: &reqwest::Error) -> bool {
+        e.is_connect() || e.is_timeout()
+    }
+
+    // `attempt` is the attempt that just failed, starting at 1
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let base = (self.initial_backoff.as_secs_f64() * exp).min(self.max_backoff.as_secs_f64());
+        let jitter = base * self.jitter * (random_unit() * 2.0 - 1.0);
+
+        secs_to_duration(base + jitter).unwrap_or(self.max_backoff)
+    }
+
+    // prefers whatever the server asked for over the computed backoff
+    pub fn delay(&self, attempt: u32, headers: Option<&HeaderMap>) -> Duration {
+        headers
+            .and_then(server_delay)
+            .map(|d| d.min(MAX_SERVER_DELAY))
+            .unwrap_or_else(|| self.backoff(attempt))
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn server_delay(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+
+    let seconds = |name: &str, scale: f64| {
+        let value = header(name)?.trim().parse::<f64>().ok()?;
+        secs_to_duration(value * scale)
+    };
+
+    if let Some(delay) = seconds("retry-after-ms", 0.001) {
+        return Some(delay);
+    }
+
+    if let Some(delay) = seconds("retry-after", 1.0) {
+        return Some(delay);
+    }
+
+    // only wait for the buckets that are actually exhausted, or for both if we can't tell
+    let exhausted = |name: &str| header(name).map(|v| v.trim() == "0");
+    let requests = header("x-ratelimit-reset-requests").and_then(parse_reset_duration);
+    let tokens = header("x-ratelimit-reset-tokens").and_then(parse_reset_duration);
+
+    match (
+        exhausted("x-ratelimit-remaining-requests"),
+        exhausted("x-ratelimit-remaining-tokens"),
+    ) {
+        (Some(true), Some(false)) => requests,
+        (Some(false), Some(true)) => tokens,
+        _ => requests.max(tokens),
+    }
+}
+
+// parses the go style durations openai uses in its reset headers, e.g. `1s`, `6m0s`, `20ms`
+pub(crate) fn parse_reset_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+
+    let mut total = 0.0;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let value: f64 = rest[..split].parse().ok()?;
+        rest = &rest[split..];
+
+        let unit_len = rest
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(rest.len());
+        let scale = match &rest[..unit_len] {
+            "h" => 3600.0,
+            "m" => 60.0,
+            "s" | "" => 1.0,
+            "ms" => 0.001,
+            "us" | "µs" => 0.000_001,
+            "ns" => 0.000_000_001,
+            _ => return None,
+        };
+        rest = &rest[unit_len..];
+
+        total += value * scale;
+    }
+
+    secs_to_duration(total)
+}
+
+// headers are untrusted, `inf`, `NaN` or negative values would make `Duration` panic
+fn secs_to_duration(secs: f64) -> Option<Duration> {
+    if !secs.is_finite() || secs < 0.0 {
+        return None;
+    }
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+// cheap randomness for jitter, not worth pulling in a rng crate for
+fn random_unit() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or_default(),
+    );
+
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn parses_reset_durations() {
+        assert_eq!(parse_reset_duration("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_reset_duration("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_reset_duration("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(
+            parse_reset_duration("1h2m3.5s"),
+            Some(Duration::from_millis(3_723_500))
+        );
+        assert_eq!(parse_reset_duration("0.5"), Some(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn rejects_invalid_reset_durations() {
+        assert_eq!(parse_reset_duration(""), None);
+        assert_eq!(parse_reset_duration("5d"), None);
+        assert_eq!(parse_reset_duration("s"), None);
+        assert_eq!(parse_reset_duration("1.2.3s"), None);
+    }
+
+    #[test]
+    fn backoff_stays_within_jitter_bounds() {
+        let policy = RetryPolicy::new()
+            .with_initial_backoff(Duration::from_millis(1000))
+            .with_max_backoff(Duration::from_secs(3))
+            .with_multiplier(2.0)
+            .with_jitter(0.25);
+
+        for _ in 0..100 {
+            let first = policy.backoff(1);
+            assert!(first >= Duration::from_millis(750) && first <= Duration::from_millis(1250));
+
+            let second = policy.backoff(2);
+            assert!(second >= Duration::from_millis(1500) && second <= Duration::from_millis(2500));
+
+            // capped at the max backoff before the jitter is applied
+            let tenth = policy.backoff(10);
+            assert!(tenth >= Duration::from_millis(2250) && tenth <= Duration::from_millis(3750));
+        }
+    }
+
+    #[test]
+    fn backoff_without_jitter_is_exact() {
+        let policy = RetryPolicy::new()
+            .with_initial_backoff(Duration::from_millis(100))
+            .with_jitter(0.0);
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn prefers_server_delay() {
+        let policy = RetryPolicy::new().with_jitter(0.0);
+
+        let delay = policy.delay(1, Some(&headers(&[("retry-after-ms", "1500")])));
+        assert_eq!(delay, Duration::from_millis(1500));
+
+        let delay = policy.delay(1, Some(&headers(&[("retry-after", "2")])));
+        assert_eq!(delay, Duration::from_secs(2));
+
+        let delay = policy.delay(1, Some(&headers(&[("retry-after", "3600")])));
+        assert_eq!(delay, MAX_SERVER_DELAY);
+    }
+
+    #[test]
+    fn ignores_invalid_server_delays() {
+        let policy = RetryPolicy::new().with_jitter(0.0);
+
+        for value in ["inf", "1e400", "NaN", "-5", "soon"] {
+            let delay = policy.delay(1, Some(&headers(&[("retry-after", value)])));
+            assert_eq!(delay, policy.backoff(1), "retry-after: {}", value);
+
+            let delay = policy.delay(1, Some(&headers(&[("retry-after-ms", value)])));
+            assert_eq!(delay, policy.backoff(1), "retry-after-ms: {}", value);
+        }
+    }
+
+    #[test]
+    fn waits_for_exhausted_bucket() {
+        let headers = headers(&[
+            ("x-ratelimit-remaining-requests", "10"),
+            ("x-ratelimit-remaining-tokens", "0"),
+            ("x-ratelimit-reset-requests", "1s"),
+            ("x-ratelimit-reset-tokens", "6m0s"),
+        ]);
+        assert_eq!(server_delay(&headers), Some(Duration::from_secs(360)));
+    }
+}
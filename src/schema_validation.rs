@@ -0,0 +1,552 @@
+use std::fmt;
+
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SchemaViolation {
+    // location of the offending value, e.g. `items[0].name`, empty for the root
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for SchemaViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "{}", self.message),
+            false => write!(f, "`{}`: {}", self.path, self.message),
+        }
+    }
+}
+
+// Everything wrong with the arguments of a function call, see `FunctionCall::parse_arguments`.
+#[derive(Debug, Clone, Error)]
+pub struct ArgumentErrors {
+    pub function_name: String,
+    pub violations: Vec<SchemaViolation>,
+}
+
+impl ArgumentErrors {
+    // a message meant for the model, so it can call the function again with fixed arguments
+    pub fn correction_message(&self) -> String {
+        let mut message = format!("The arguments for `{}` are invalid:\n", self.function_name);
+        for violation in &self.violations {
+            message.push_str(&format!("- {}\n", violation));
+        }
+        message.push_str("Call the function again with corrected arguments.");
+        message
+    }
+}
+
+impl fmt::Display for ArgumentErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid arguments for function {}: ", self.function_name)?;
+        for (i, violation) in self.violations.iter().enumerate() {
+            if i > 0 {
+                write!(f, "; ")?;
+            }
+            write!(f, "{}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+// Validates `instance` against a json schema, covering the subset of keywords schemars
+// generates. Unknown keywords are ignored.
+pub fn validate(schema: &Value, instance: &Value) -> Vec<SchemaViolation> {
+    let mut violations = vec![];
+    Validator { root: schema }.validate(schema, instance, "", &mut violations);
+    violations
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+}
+
+impl<'a> Validator<'a> {
+    fn resolve(&self, reference: &str) -> Option<&'a Value> {
+        let pointer = reference.strip_prefix('#')?;
+        self.root.pointer(pointer)
+    }
+
+    fn matches_type(&self, schema: &Value, instance: &Value) -> bool {
+        let schema = match schema.get("$ref").and_then(Value::as_str) {
+            Some(reference) => match self.resolve(reference) {
+                Some(resolved) => resolved,
+                None => return false,
+            },
+            None => schema,
+        };
+
+        match schema.get("type") {
+            Some(Value::String(t)) => is_type(instance, t),
+            Some(Value::Array(ts)) => ts
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|t| is_type(instance, t)),
+            _ => true,
+        }
+    }
+
+    fn validate(
+        &self,
+        schema: &Value,
+        instance: &Value,
+        path: &str,
+        violations: &mut Vec<SchemaViolation>,
+    ) {
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => {
+                return violations.push(violation(path, "no value is allowed here"));
+            }
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+
+        if let Some(Value::String(reference)) = schema.get("$ref") {
+            match self.resolve(reference) {
+                Some(resolved) => self.validate(resolved, instance, path, violations),
+                None => violations.push(violation(
+                    path,
+                    &format!("schema reference {} cannot be resolved", reference),
+                )),
+            }
+        }
+
+        if let Some(types) = schema.get("type") {
+            let types: Vec<&str> = match types {
+                Value::String(t) => vec![t.as_str()],
+                Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+                _ => vec![],
+            };
+
+            if !types.is_empty() && !types.iter().any(|t| is_type(instance, t)) {
+                violations.push(violation(
+                    path,
+                    &format!(
+                        "expected {}, got {}",
+                        types.join(" or "),
+                        type_name(instance)
+                    ),
+                ));
+                // everything below assumes the right type
+                return;
+            }
+        }
+
+        if let Some(Value::Array(values)) = schema.get("enum") {
+            if !values.contains(instance) {
+                violations.push(violation(
+                    path,
+                    &format!("expected one of {}, got {}", join_values(values), instance),
+                ));
+            }
+        }
+
+        if let Some(value) = schema.get("const") {
+            if value != instance {
+                violations.push(violation(
+                    path,
+                    &format!("expected {}, got {}", value, instance),
+                ));
+            }
+        }
+
+        for keyword in ["allOf", "anyOf", "oneOf"] {
+            let Some(Value::Array(schemas)) = schema.get(keyword) else {
+                continue;
+            };
+
+            let results: Vec<Vec<SchemaViolation>> = schemas
+                .iter()
+                .map(|schema| {
+                    let mut violations = vec![];
+                    self.validate(schema, instance, path, &mut violations);
+                    violations
+                })
+                .collect();
+            let matching = results.iter().filter(|r| r.is_empty()).count();
+
+            match keyword {
+                "allOf" => violations.extend(results.into_iter().flatten()),
+                "anyOf" if matching == 0 => {
+                    // report the closest alternative, that's usually the one that was meant.
+                    // one of the right type is closer than any of the wrong type.
+                    let closest = schemas
+                        .iter()
+                        .zip(results)
+                        .min_by_key(|(schema, r)| (!self.matches_type(schema, instance), r.len()))
+                        .map(|(_, r)| r);
+                    match closest {
+                        Some(closest) if !closest.is_empty() => violations.extend(closest),
+                        _ => {
+                            violations.push(violation(path, "matches none of the allowed schemas"))
+                        }
+                    }
+                }
+                "oneOf" if matching != 1 => violations.push(violation(
+                    path,
+                    &format!("must match exactly one schema, matched {}", matching),
+                )),
+                _ => {}
+            }
+        }
+
+        match instance {
+            Value::Object(object) => self.validate_object(schema, object, path, violations),
+            Value::Array(items) => self.validate_array(schema, items, path, violations),
+            Value::String(s) => validate_string(schema, s, path, violations),
+            Value::Number(_) => validate_number(schema, instance, path, violations),
+            _ => {}
+        }
+    }
+
+    fn validate_object(
+        &self,
+        schema: &Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &str,
+        violations: &mut Vec<SchemaViolation>,
+    ) {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    violations.push(violation(
+                        &join_path(path, name),
+                        "missing required property",
+                    ));
+                }
+            }
+        }
+
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, value) in object {
+            let property_path = join_path(path, name);
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property) => self.validate(property, value, &property_path, violations),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        violations.push(violation(&property_path, "unknown property"))
+                    }
+                    Some(additional) => {
+                        self.validate(additional, value, &property_path, violations)
+                    }
+                    None => {}
+                },
+            }
+        }
+    }
+
+    fn validate_array(
+        &self,
+        schema: &Map<String, Value>,
+        items: &[Value],
+        path: &str,
+        violations: &mut Vec<SchemaViolation>,
+    ) {
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if (items.len() as u64) < min {
+                violations.push(violation(path, &format!("expected at least {} items", min)));
+            }
+        }
+
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if items.len() as u64 > max {
+                violations.push(violation(path, &format!("expected at most {} items", max)));
+            }
+        }
+
+        match schema.get("items") {
+            // tuples
+            Some(Value::Array(schemas)) => {
+                for (i, (schema, item)) in schemas.iter().zip(items).enumerate() {
+                    self.validate(schema, item, &format!("{}[{}]", path, i), violations);
+                }
+            }
+            Some(schema) => {
+                for (i, item) in items.iter().enumerate() {
+                    self.validate(schema, item, &format!("{}[{}]", path, i), violations);
+                }
+            }
+            None => {}
+        }
+    }
+}
+
+fn validate_string(
+    schema: &Map<String, Value>,
+    s: &str,
+    path: &str,
+    violations: &mut Vec<SchemaViolation>,
+) {
+    let len = s.chars().count() as u64;
+
+    if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+        if len < min {
+            violations.push(violation(
+                path,
+                &format!("expected at least {} characters", min),
+            ));
+        }
+    }
+
+    if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+        if len > max {
+            violations.push(violation(
+                path,
+                &format!("expected at most {} characters", max),
+            ));
+        }
+    }
+}
+
+fn validate_number(
+    schema: &Map<String, Value>,
+    instance: &Value,
+    path: &str,
+    violations: &mut Vec<SchemaViolation>,
+) {
+    let Some(n) = instance.as_f64() else {
+        return;
+    };
+    let bound = |keyword: &str| schema.get(keyword).and_then(Value::as_f64);
+
+    if let Some(min) = bound("minimum").filter(|min| n < *min) {
+        violations.push(violation(path, &format!("must be at least {}", min)));
+    }
+
+    if let Some(max) = bound("maximum").filter(|max| n > *max) {
+        violations.push(violation(path, &format!("must be at most {}", max)));
+    }
+
+    if let Some(min) = bound("exclusiveMinimum").filter(|min| n <= *min) {
+        violations.push(violation(path, &format!("must be greater than {}", min)));
+    }
+
+    if let Some(max) = bound("exclusiveMaximum").filter(|max| n >= *max) {
+        violations.push(violation(path, &format!("must be less than {}", max)));
+    }
+
+    // schemars describes the width of integers only through their format
+    let range = match schema.get("format").and_then(Value::as_str) {
+        Some("int8") => Some((i8::MIN as f64, i8::MAX as f64)),
+        Some("uint8") => Some((0.0, u8::MAX as f64)),
+        Some("int16") => Some((i16::MIN as f64, i16::MAX as f64)),
+        Some("uint16") => Some((0.0, u16::MAX as f64)),
+        Some("int32") => Some((i32::MIN as f64, i32::MAX as f64)),
+        Some("uint32") => Some((0.0, u32::MAX as f64)),
+        _ => None,
+    };
+    if let Some((min, max)) = range.filter(|(min, max)| n < *min || n > *max) {
+        violations.push(violation(
+            path,
+            &format!("must be between {} and {}", min, max),
+        ));
+    }
+}
+
+fn is_type(instance: &Value, t: &str) -> bool {
+    match t {
+        "null" => instance.is_null(),
+        "boolean" => instance.is_boolean(),
+        "object" => instance.is_object(),
+        "array" => instance.is_array(),
+        "string" => instance.is_string(),
+        "number" => instance.is_number(),
+        "integer" => {
+            instance.is_i64()
+                || instance.is_u64()
+                || instance.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        _ => true,
+    }
+}
+
+fn type_name(instance: &Value) -> &'static str {
+    match instance {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+fn join_values(values: &[Value]) -> String {
+    values
+        .iter()
+        .map(Value::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn join_path(path: &str, name: &str) -> String {
+    match path.is_empty() {
+        true => name.to_string(),
+        false => format!("{}.{}", path, name),
+    }
+}
+
+fn violation(path: &str, message: &str) -> SchemaViolation {
+    SchemaViolation {
+        path: path.to_string(),
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn paths(violations: &[SchemaViolation]) -> Vec<&str> {
+        violations.iter().map(|v| v.path.as_str()).collect()
+    }
+
+    #[test]
+    fn accepts_valid_instances() {
+        let schema = json!({
+            "type": "object",
+            "required": ["name", "tags"],
+            "properties": {
+                "name": { "type": "string" },
+                "tags": { "type": "array", "items": { "type": "string" } },
+                "age": { "type": ["integer", "null"] }
+            }
+        });
+
+        let instance = json!({ "name": "a", "tags": ["x", "y"], "age": null });
+        assert!(validate(&schema, &instance).is_empty());
+    }
+
+    #[test]
+    fn reports_type_mismatches() {
+        let schema = json!({ "type": "object", "properties": { "count": { "type": "integer" } } });
+
+        let violations = validate(&schema, &json!({ "count": "three" }));
+        assert_eq!(paths(&violations), vec!["count"]);
+        assert_eq!(violations[0].message, "expected integer, got string");
+
+        let violations = validate(&schema, &json!({ "count": 1.5 }));
+        assert_eq!(violations[0].message, "expected integer, got number");
+
+        assert!(validate(&schema, &json!({ "count": 2.0 })).is_empty());
+
+        let violations = validate(&schema, &json!([]));
+        assert_eq!(paths(&violations), vec![""]);
+        assert_eq!(violations[0].message, "expected object, got array");
+    }
+
+    #[test]
+    fn reports_missing_required_properties() {
+        let schema = json!({
+            "type": "object",
+            "required": ["a", "b"],
+            "properties": { "a": { "type": "string" }, "b": { "type": "string" } }
+        });
+
+        let violations = validate(&schema, &json!({ "a": "x" }));
+        assert_eq!(paths(&violations), vec!["b"]);
+        assert_eq!(violations[0].message, "missing required property");
+    }
+
+    #[test]
+    fn reports_values_outside_the_enum() {
+        let schema = json!({ "type": "string", "enum": ["red", "green"] });
+
+        assert!(validate(&schema, &json!("red")).is_empty());
+
+        let violations = validate(&schema, &json!("blue"));
+        assert_eq!(
+            violations[0].message,
+            r#"expected one of "red", "green", got "blue""#
+        );
+    }
+
+    #[test]
+    fn reports_nested_paths() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "items": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["id"],
+                        "properties": { "id": { "type": "integer" } },
+                        "additionalProperties": false
+                    }
+                }
+            }
+        });
+
+        let instance = json!({ "items": [{ "id": 1 }, { "id": "2" }, { "extra": true }] });
+        let violations = validate(&schema, &instance);
+        assert_eq!(
+            paths(&violations),
+            vec!["items[1].id", "items[2].id", "items[2].extra"]
+        );
+    }
+
+    #[test]
+    fn resolves_references() {
+        let schema = json!({
+            "type": "object",
+            "required": ["point"],
+            "properties": { "point": { "$ref": "#/definitions/Point" } },
+            "definitions": {
+                "Point": {
+                    "type": "object",
+                    "required": ["x", "y"],
+                    "properties": { "x": { "type": "number" }, "y": { "type": "number" } }
+                }
+            }
+        });
+
+        assert!(validate(&schema, &json!({ "point": { "x": 1, "y": 2 } })).is_empty());
+
+        let violations = validate(&schema, &json!({ "point": { "x": 1 } }));
+        assert_eq!(paths(&violations), vec!["point.y"]);
+    }
+
+    #[test]
+    fn reports_unresolvable_references() {
+        let schema = json!({ "$ref": "#/definitions/Missing" });
+
+        let violations = validate(&schema, &json!(1));
+        assert_eq!(
+            violations[0].message,
+            "schema reference #/definitions/Missing cannot be resolved"
+        );
+    }
+
+    #[test]
+    fn any_of_reports_the_closest_alternative() {
+        let schema = json!({
+            "anyOf": [
+                { "type": "string" },
+                {
+                    "type": "object",
+                    "required": ["a", "b"],
+                    "properties": { "a": { "type": "integer" }, "b": { "type": "integer" } }
+                }
+            ]
+        });
+
+        assert!(validate(&schema, &json!("x")).is_empty());
+
+        let violations = validate(&schema, &json!({ "a": 1 }));
+        assert_eq!(paths(&violations), vec!["b"]);
+    }
+
+    #[test]
+    fn checks_integer_formats() {
+        let schema = json!({ "type": "integer", "format": "uint8" });
+
+        assert!(validate(&schema, &json!(255)).is_empty());
+        assert_eq!(validate(&schema, &json!(256)).len(), 1);
+        assert_eq!(validate(&schema, &json!(-1)).len(), 1);
+    }
+}
@@ -0,0 +1,472 @@
+use std::fmt;
+
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+// keywords strict mode rejects, they are dropped instead of failing the request
+const UNSUPPORTED_KEYWORDS: &[&str] = &[
+    "$schema",
+    "$id",
+    "title",
+    "default",
+    "examples",
+    "format",
+    "pattern",
+    "minLength",
+    "maxLength",
+    "minimum",
+    "maximum",
+    "exclusiveMinimum",
+    "exclusiveMaximum",
+    "multipleOf",
+    "minItems",
+    "maxItems",
+    "uniqueItems",
+    "minProperties",
+    "maxProperties",
+    "patternProperties",
+    "propertyNames",
+    "contains",
+    "readOnly",
+    "writeOnly",
+    "nullable",
+];
+
+#[derive(Debug, Clone, Error)]
+pub struct StrictSchemaError {
+    // json pointer to the offending schema
+    pub path: String,
+    pub reason: String,
+}
+
+impl fmt::Display for StrictSchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.path.is_empty() {
+            true => write!(f, "unsupported schema for strict mode: {}", self.reason),
+            false => write!(
+                f,
+                "unsupported schema for strict mode at {}: {}",
+                self.path, self.reason
+            ),
+        }
+    }
+}
+
+// Rewrites a schemars schema into the subset openai accepts with `strict: true`: definitions
+// move to `$defs`, every object gets `additionalProperties: false` and lists all of its
+// properties as required, and keywords strict mode doesn't know about are removed.
+pub fn to_strict_schema(schema: Value) -> Result<Value, StrictSchemaError> {
+    let Value::Object(mut root) = schema else {
+        return Err(error("", "the root schema must be an object schema"));
+    };
+
+    let mut defs = Map::new();
+    for key in ["definitions", "$defs"] {
+        if let Some(Value::Object(definitions)) = root.remove(key) {
+            defs.extend(definitions);
+        }
+    }
+
+    if root.get("type").and_then(Value::as_str) != Some("object") {
+        return Err(error("", "the root schema must be of type object"));
+    }
+
+    let mut root = transform(Value::Object(root), "")?;
+    if !defs.is_empty() {
+        let defs = defs
+            .into_iter()
+            .map(|(name, schema)| {
+                let schema = transform(schema, &format!("/$defs/{}", name))?;
+                Ok((name, schema))
+            })
+            .collect::<Result<Map<String, Value>, StrictSchemaError>>()?;
+        root.as_object_mut()
+            .expect("transform keeps objects")
+            .insert("$defs".to_string(), Value::Object(defs));
+    }
+
+    Ok(root)
+}
+
+fn transform(schema: Value, path: &str) -> Result<Value, StrictSchemaError> {
+    let mut schema = match schema {
+        Value::Object(schema) => schema,
+        Value::Bool(true) => {
+            return Err(error(path, "schemas accepting any value are not supported"))
+        }
+        _ => return Err(error(path, "expected a schema object")),
+    };
+
+    for keyword in UNSUPPORTED_KEYWORDS {
+        schema.remove(*keyword);
+    }
+
+    // schemars wraps references in a single element `allOf` to attach a description
+    if let Some(Value::Array(all_of)) = schema.remove("allOf") {
+        match <[Value; 1]>::try_from(all_of) {
+            Ok([inner]) => {
+                let Value::Object(inner) = inner else {
+                    return Err(error(path, "expected a schema object in allOf"));
+                };
+                for (key, value) in inner {
+                    schema.entry(key).or_insert(value);
+                }
+                for keyword in UNSUPPORTED_KEYWORDS {
+                    schema.remove(*keyword);
+                }
+            }
+            Err(_) => {
+                return Err(error(
+                    path,
+                    "allOf with more than one schema is not supported",
+                ))
+            }
+        }
+    }
+
+    if let Some(Value::String(reference)) = schema.get("$ref") {
+        let name = reference
+            .strip_prefix("#/definitions/")
+            .or_else(|| reference.strip_prefix("#/$defs/"))
+            .ok_or_else(|| error(path, &format!("unsupported reference {}", reference)))?;
+
+        // siblings of a reference are not allowed
+        let mut reference = Map::new();
+        reference.insert(
+            "$ref".to_string(),
+            Value::String(format!("#/$defs/{}", name)),
+        );
+        return Ok(Value::Object(reference));
+    }
+
+    if let Some(one_of) = schema.remove("oneOf") {
+        schema.insert("anyOf".to_string(), one_of);
+    }
+
+    if let Some(Value::Array(any_of)) = schema.remove("anyOf") {
+        let any_of = any_of
+            .into_iter()
+            .enumerate()
+            .map(|(i, schema)| transform(schema, &format!("{}/anyOf/{}", path, i)))
+            .collect::<Result<Vec<Value>, StrictSchemaError>>()?;
+        schema.insert("anyOf".to_string(), Value::Array(any_of));
+        return Ok(Value::Object(schema));
+    }
+
+    if has_type(&schema, "object") {
+        transform_object(&mut schema, path)?;
+    }
+
+    if has_type(&schema, "array") {
+        match schema.remove("items") {
+            Some(Value::Array(_)) => return Err(error(path, "tuples are not supported")),
+            Some(items) => {
+                let items = transform(items, &format!("{}/items", path))?;
+                schema.insert("items".to_string(), items);
+            }
+            None => return Err(error(path, "arrays need an items schema")),
+        }
+    }
+
+    if !schema.contains_key("type") && !schema.contains_key("enum") && !schema.contains_key("const")
+    {
+        return Err(error(path, "every schema needs a type"));
+    }
+
+    Ok(Value::Object(schema))
+}
+
+fn has_type(schema: &Map<String, Value>, t: &str) -> bool {
+    match schema.get("type") {
+        Some(Value::String(s)) => s == t,
+        Some(Value::Array(ts)) => ts.iter().any(|s| s == t),
+        _ => false,
+    }
+}
+
+fn transform_object(schema: &mut Map<String, Value>, path: &str) -> Result<(), StrictSchemaError> {
+    match schema.get("additionalProperties") {
+        None | Some(Value::Bool(false)) => {}
+        Some(Value::Bool(true)) | Some(Value::Object(_)) => {
+            return Err(error(
+                path,
+                "maps and objects with additional properties are not supported",
+            ))
+        }
+        Some(_) => return Err(error(path, "invalid additionalProperties")),
+    }
+    schema.insert("additionalProperties".to_string(), Value::Bool(false));
+
+    let required: Vec<String> = match schema.get("required") {
+        Some(Value::Array(required)) => required
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_string)
+            .collect(),
+        _ => vec![],
+    };
+
+    let properties = match schema.remove("properties") {
+        Some(Value::Object(properties)) => properties,
+        None => Map::new(),
+        Some(_) => return Err(error(path, "invalid properties")),
+    };
+
+    // strict mode wants every property listed as required. optional ones are already nullable
+    // when they are `Option`s, anything else that is optional has a `#[serde(default)]` and can't
+    // take `null`, the model has to fill those in.
+    let mut names = vec![];
+    let mut transformed = Map::new();
+    for (name, property) in properties {
+        let property_path = format!("{}/properties/{}", path, name);
+        // openapi style schemas mark `Option`s with a keyword strict mode doesn't know
+        let nullable = property.get("nullable") == Some(&Value::Bool(true));
+        let mut property = transform(property, &property_path)?;
+        if nullable && !required.contains(&name) {
+            property = make_nullable(property);
+        }
+        names.push(Value::String(name.clone()));
+        transformed.insert(name, property);
+    }
+
+    schema.insert("properties".to_string(), Value::Object(transformed));
+    schema.insert("required".to_string(), Value::Array(names));
+    Ok(())
+}
+
+// `null` stands in for a missing value of an optional property
+fn make_nullable(schema: Value) -> Value {
+    let Value::Object(mut schema) = schema else {
+        return schema;
+    };
+    let null = Value::String("null".to_string());
+
+    if schema.contains_key("$ref") {
+        let mut null_schema = Map::new();
+        null_schema.insert("type".to_string(), null);
+
+        let mut any_of = Map::new();
+        any_of.insert(
+            "anyOf".to_string(),
+            Value::Array(vec![Value::Object(schema), Value::Object(null_schema)]),
+        );
+        return Value::Object(any_of);
+    }
+
+    if let Some(Value::Array(any_of)) = schema.get_mut("anyOf") {
+        if !any_of.iter().any(|s| s.get("type") == Some(&null)) {
+            let mut null_schema = Map::new();
+            null_schema.insert("type".to_string(), null);
+            any_of.push(Value::Object(null_schema));
+        }
+        return Value::Object(schema);
+    }
+
+    if let Some(Value::Array(values)) = schema.get_mut("enum") {
+        if !values.contains(&Value::Null) {
+            values.push(Value::Null);
+        }
+    }
+
+    match schema.remove("type") {
+        Some(Value::String(t)) if t == "null" => {
+            schema.insert("type".to_string(), Value::String(t));
+        }
+        Some(Value::String(t)) => {
+            schema.insert(
+                "type".to_string(),
+                Value::Array(vec![Value::String(t), null]),
+            );
+        }
+        Some(Value::Array(mut types)) => {
+            if !types.contains(&null) {
+                types.push(null);
+            }
+            schema.insert("type".to_string(), Value::Array(types));
+        }
+        Some(t) => {
+            schema.insert("type".to_string(), t);
+        }
+        None => {}
+    }
+
+    Value::Object(schema)
+}
+
+fn error(path: &str, reason: &str) -> StrictSchemaError {
+    StrictSchemaError {
+        path: path.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use schemars::{schema_for, JsonSchema};
+    use serde_json::json;
+
+    use super::*;
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema, serde_derive::Deserialize)]
+    struct Address {
+        city: String,
+        zip: Option<String>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema, serde_derive::Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    enum Kind {
+        Home,
+        Work,
+    }
+
+    #[allow(dead_code)]
+    #[derive(JsonSchema, serde_derive::Deserialize)]
+    struct Person {
+        /// the full name
+        name: String,
+        #[serde(default)]
+        age: u32,
+        nickname: Option<String>,
+        /// where they live
+        address: Address,
+        previous: Option<Address>,
+        kind: Kind,
+        tags: Vec<String>,
+    }
+
+    fn strict<T: JsonSchema>() -> Result<Value, StrictSchemaError> {
+        to_strict_schema(serde_json::to_value(schema_for!(T)).unwrap())
+    }
+
+    #[test]
+    fn every_property_is_required() {
+        let schema = strict::<Person>().unwrap();
+
+        assert_eq!(schema["additionalProperties"], json!(false));
+        assert_eq!(
+            schema["required"],
+            json!(["address", "age", "kind", "name", "nickname", "previous", "tags"])
+        );
+
+        let address = &schema["$defs"]["Address"];
+        assert_eq!(address["additionalProperties"], json!(false));
+        assert_eq!(address["required"], json!(["city", "zip"]));
+    }
+
+    #[test]
+    fn options_are_nullable_and_defaults_are_not() {
+        let schema = strict::<Person>().unwrap();
+        let properties = &schema["properties"];
+
+        assert_eq!(properties["nickname"]["type"], json!(["string", "null"]));
+        assert_eq!(properties["age"]["type"], json!("integer"));
+        assert!(properties["age"].get("default").is_none());
+        assert!(properties["age"].get("format").is_none());
+        assert_eq!(
+            properties["previous"]["anyOf"],
+            json!([{ "$ref": "#/$defs/Address" }, { "type": "null" }])
+        );
+
+        // what the model sends back has to deserialize into the type
+        let person: Person = serde_json::from_value(json!({
+            "name": "Ada",
+            "age": 36,
+            "nickname": null,
+            "address": { "city": "London", "zip": null },
+            "previous": null,
+            "kind": "home",
+            "tags": []
+        }))
+        .unwrap();
+        assert_eq!(person.age, 36);
+    }
+
+    #[test]
+    fn references_lose_their_siblings() {
+        let schema = strict::<Person>().unwrap();
+
+        assert_eq!(
+            schema["properties"]["address"],
+            json!({ "$ref": "#/$defs/Address" })
+        );
+        assert_eq!(schema["$defs"]["Kind"]["enum"], json!(["home", "work"]));
+        assert!(schema.get("definitions").is_none());
+    }
+
+    #[test]
+    fn openapi_nullable_becomes_a_null_type() {
+        let schema = to_strict_schema(json!({
+            "type": "object",
+            "properties": { "note": { "type": "string", "nullable": true } }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            schema["properties"]["note"],
+            json!({ "type": ["string", "null"] })
+        );
+    }
+
+    #[test]
+    fn one_of_becomes_any_of() {
+        let schema = to_strict_schema(json!({
+            "type": "object",
+            "properties": {
+                "value": { "oneOf": [{ "type": "string" }, { "type": "integer" }] }
+            },
+            "required": ["value"]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            schema["properties"]["value"],
+            json!({ "anyOf": [{ "type": "string" }, { "type": "integer" }] })
+        );
+    }
+
+    #[test]
+    fn rejects_maps() {
+        #[allow(dead_code)]
+        #[derive(JsonSchema)]
+        struct Scores {
+            scores: HashMap<String, u32>,
+        }
+
+        let e = strict::<Scores>().unwrap_err();
+        assert_eq!(e.path, "/properties/scores");
+    }
+
+    #[test]
+    fn rejects_tuples() {
+        #[allow(dead_code)]
+        #[derive(JsonSchema)]
+        struct Point {
+            xy: (f64, f64),
+        }
+
+        let e = strict::<Point>().unwrap_err();
+        assert_eq!(e.reason, "tuples are not supported");
+    }
+
+    #[test]
+    fn rejects_non_object_roots() {
+        assert!(strict::<String>().is_err());
+        assert!(to_strict_schema(json!(true)).is_err());
+    }
+
+    #[test]
+    fn rejects_typeless_schemas() {
+        let e = to_strict_schema(json!({
+            "type": "object",
+            "properties": { "anything": {} },
+            "required": ["anything"]
+        }))
+        .unwrap_err();
+        assert_eq!(e.path, "/properties/anything");
+    }
+}
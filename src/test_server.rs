@@ -0,0 +1,153 @@
+// A tiny http server for tests. Every connection is answered with the next canned reply
+// and the request bodies are kept around so tests can check what was sent.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+use crate::{Client, RetryPolicy};
+
+pub(crate) struct Reply {
+    head: String,
+    body: String,
+    // keep the connection open after the body instead of closing it
+    hang: bool,
+}
+
+impl Reply {
+    pub(crate) fn json(status: u16, body: &Value) -> Self {
+        Self::text(status, "application/json", &body.to_string())
+    }
+
+    pub(crate) fn text(status: u16, content_type: &str, body: &str) -> Self {
+        Self {
+            head: format!(
+                "HTTP/1.1 {} Test\r\ncontent-type: {}\r\ncontent-length: {}\r\nx-request-id: req_test\r\nconnection: close\r\n\r\n",
+                status,
+                content_type,
+                body.len()
+            ),
+            body: body.to_string(),
+            hang: false,
+        }
+    }
+
+    // server sent events, one `data:` line per event
+    pub(crate) fn events(events: &[Value]) -> Self {
+        let body: String = events.iter().map(|e| format!("data: {}\n\n", e)).collect();
+        Self {
+            head: "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n"
+                .to_string(),
+            body,
+            hang: false,
+        }
+    }
+
+    pub(crate) fn hang(mut self) -> Self {
+        self.hang = true;
+        self
+    }
+}
+
+pub(crate) struct TestServer {
+    url: String,
+    requests: Arc<Mutex<Vec<Value>>>,
+    closed: Arc<Notify>,
+}
+
+impl TestServer {
+    pub(crate) async fn start(replies: Vec<Reply>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let closed = Arc::new(Notify::new());
+
+        let (server_requests, server_closed) = (requests.clone(), closed.clone());
+        tokio::spawn(async move {
+            for reply in replies {
+                let Ok((socket, _)) = listener.accept().await else {
+                    return;
+                };
+                let (requests, closed) = (server_requests.clone(), server_closed.clone());
+                tokio::spawn(async move { serve(socket, reply, requests, closed).await });
+            }
+        });
+
+        Self {
+            url,
+            requests,
+            closed,
+        }
+    }
+
+    pub(crate) fn client(&self) -> Client {
+        Client::new()
+            .with_base_url(&self.url)
+            .with_api_key("test")
+            .with_retry_policy(RetryPolicy::none())
+    }
+
+    pub(crate) fn requests(&self) -> Vec<Value> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // resolves once the client hung up on a reply that was kept open
+    pub(crate) async fn closed(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, self.closed.notified())
+            .await
+            .is_ok()
+    }
+}
+
+async fn serve(
+    mut socket: TcpStream,
+    reply: Reply,
+    requests: Arc<Mutex<Vec<Value>>>,
+    closed: Arc<Notify>,
+) {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let body_start = loop {
+        let Ok(n) = socket.read(&mut chunk).await else {
+            return;
+        };
+        if n == 0 {
+            return;
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(end) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..body_start]).to_lowercase();
+    let length = head
+        .lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    while buffer.len() < body_start + length {
+        match socket.read(&mut chunk).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => buffer.extend_from_slice(&chunk[..n]),
+        }
+    }
+    let body = serde_json::from_slice(&buffer[body_start..body_start + length]).unwrap_or_default();
+    requests.lock().unwrap().push(body);
+
+    if socket.write_all(reply.head.as_bytes()).await.is_err()
+        || socket.write_all(reply.body.as_bytes()).await.is_err()
+    {
+        return;
+    }
+
+    if reply.hang {
+        // nothing more is sent, so a read only returns once the client closed the connection
+        let _ = socket.read(&mut chunk).await;
+        closed.notify_one();
+    }
+}
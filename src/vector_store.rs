@@ -0,0 +1,770 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::error::{Error, InternalError, UtilsResult};
+use crate::{Client, Embeddings};
+
+// identifies the binary format, followed by its version
+const MAGIC: &[u8; 4] = b"OAVS";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Document {
+    pub id: String,
+    pub text: String,
+
+    #[serde(default)]
+    pub metadata: BTreeMap<String, Value>,
+
+    // empty until the document has been embedded
+    #[serde(default)]
+    pub embedding: Vec<f32>,
+}
+
+impl Document {
+    pub fn new(id: impl Into<String>, text: impl Into<String>) -> Self {
+        Self {
+            id: id.into(),
+            text: text.into(),
+            metadata: BTreeMap::new(),
+            embedding: vec![],
+        }
+    }
+
+    pub fn with_metadata(mut self, key: impl Into<String>, value: impl Into<Value>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
+    }
+
+    pub fn with_embedding(mut self, embedding: Vec<f32>) -> Self {
+        self.embedding = embedding;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Similarity {
+    #[default]
+    Cosine,
+    // the same as cosine for normalized embeddings like openai's, but cheaper
+    DotProduct,
+}
+
+impl Similarity {
+    pub fn score(&self, a: &[f32], b: &[f32]) -> f32 {
+        let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+        match self {
+            Similarity::DotProduct => dot,
+            Similarity::Cosine => {
+                let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+                let norms = norm(a) * norm(b);
+                if norms == 0.0 {
+                    0.0
+                } else {
+                    dot / norms
+                }
+            }
+        }
+    }
+}
+
+// Conditions on the metadata of documents, keys that are missing never match.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Eq(String, Value),
+    In(String, Vec<Value>),
+    Exists(String),
+    Not(Box<Filter>),
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+}
+
+impl Filter {
+    pub fn eq(key: impl Into<String>, value: impl Into<Value>) -> Self {
+        Filter::Eq(key.into(), value.into())
+    }
+
+    pub fn is_in(key: impl Into<String>, values: Vec<Value>) -> Self {
+        Filter::In(key.into(), values)
+    }
+
+    pub fn exists(key: impl Into<String>) -> Self {
+        Filter::Exists(key.into())
+    }
+
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Filter::And(mut filters) => {
+                filters.push(other);
+                Filter::And(filters)
+            }
+            filter => Filter::And(vec![filter, other]),
+        }
+    }
+
+    pub fn or(self, other: Filter) -> Self {
+        match self {
+            Filter::Or(mut filters) => {
+                filters.push(other);
+                Filter::Or(filters)
+            }
+            filter => Filter::Or(vec![filter, other]),
+        }
+    }
+
+    pub fn matches(&self, metadata: &BTreeMap<String, Value>) -> bool {
+        match self {
+            Filter::Eq(key, value) => metadata.get(key) == Some(value),
+            Filter::In(key, values) => metadata.get(key).is_some_and(|v| values.contains(v)),
+            Filter::Exists(key) => metadata.contains_key(key),
+            Filter::Not(filter) => !filter.matches(metadata),
+            Filter::And(filters) => filters.iter().all(|filter| filter.matches(metadata)),
+            Filter::Or(filters) => filters.iter().any(|filter| filter.matches(metadata)),
+        }
+    }
+}
+
+impl std::ops::Not for Filter {
+    type Output = Filter;
+
+    fn not(self) -> Filter {
+        Filter::Not(Box::new(self))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SearchResult<'a> {
+    pub document: &'a Document,
+    pub score: f32,
+}
+
+// A flat index kept in memory, every search compares the query against every document.
+// Good enough for a few ten thousand documents, beyond that use a proper vector database.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(try_from = "StoredVectorStore")]
+pub struct VectorStore {
+    pub similarity: Similarity,
+    documents: Vec<Document>,
+
+    // position of every document in `documents` by id
+    #[serde(skip)]
+    positions: HashMap<String, usize>,
+}
+
+// what gets deserialized, the documents are checked and indexed on the way into the store
+#[derive(Deserialize)]
+struct StoredVectorStore {
+    #[serde(default)]
+    similarity: Similarity,
+    documents: Vec<Document>,
+}
+
+impl TryFrom<StoredVectorStore> for VectorStore {
+    type Error = Error;
+
+    fn try_from(stored: StoredVectorStore) -> UtilsResult<Self> {
+        let mut store = VectorStore::new().with_similarity(stored.similarity);
+        stored
+            .documents
+            .into_iter()
+            .try_for_each(|document| store.add(document))?;
+        Ok(store)
+    }
+}
+
+impl VectorStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_similarity(mut self, similarity: Similarity) -> Self {
+        self.similarity = similarity;
+        self
+    }
+
+    pub fn documents(&self) -> &[Document] {
+        &self.documents
+    }
+
+    pub fn len(&self) -> usize {
+        self.documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.documents.is_empty()
+    }
+
+    pub fn dimensions(&self) -> Option<usize> {
+        self.documents
+            .first()
+            .map(|document| document.embedding.len())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Document> {
+        self.positions
+            .get(id)
+            .map(|&position| &self.documents[position])
+    }
+
+    // replaces a document with the same id, all embeddings need the same dimensions
+    pub fn add(&mut self, document: Document) -> UtilsResult<()> {
+        if document.embedding.is_empty() {
+            Err(InternalError::ConfigurationError(format!(
+                "document {} has no embedding",
+                document.id
+            )))?
+        }
+
+        if let Some(dimensions) = self.dimensions() {
+            if document.embedding.len() != dimensions {
+                Err(InternalError::DimensionMismatch(
+                    dimensions,
+                    document.embedding.len(),
+                ))?
+            }
+        }
+
+        match self.positions.get(&document.id) {
+            Some(&position) => self.documents[position] = document,
+            None => {
+                self.positions
+                    .insert(document.id.clone(), self.documents.len());
+                self.documents.push(document);
+            }
+        }
+        Ok(())
+    }
+
+    // the last document takes the place of the removed one
+    pub fn remove(&mut self, id: &str) -> Option<Document> {
+        let position = self.positions.remove(id)?;
+        let document = self.documents.swap_remove(position);
+        if let Some(moved) = self.documents.get(position) {
+            self.positions.insert(moved.id.clone(), position);
+        }
+        Some(document)
+    }
+
+    // removes every document matching the filter and returns how many there were
+    pub fn remove_where(&mut self, filter: &Filter) -> usize {
+        let len = self.documents.len();
+        self.documents
+            .retain(|document| !filter.matches(&document.metadata));
+
+        if self.documents.len() != len {
+            self.positions = self
+                .documents
+                .iter()
+                .enumerate()
+                .map(|(position, document)| (document.id.clone(), position))
+                .collect();
+        }
+        len - self.documents.len()
+    }
+
+    // embeds the documents that don't have an embedding yet in as few requests as possible
+    pub async fn embed_and_add(
+        &mut self,
+        embeddings: &Embeddings,
+        client: &Client,
+        mut documents: Vec<Document>,
+    ) -> UtilsResult<()> {
+        let missing: Vec<&mut Document> = documents
+            .iter_mut()
+            .filter(|document| document.embedding.is_empty())
+            .collect();
+
+        if !missing.is_empty() {
+            let texts: Vec<String> = missing
+                .iter()
+                .map(|document| document.text.clone())
+                .collect();
+            let vectors = embeddings.create_with(client, texts).await?.vectors();
+            if vectors.len() != missing.len() {
+                Err(InternalError::NoEmbeddings)?
+            }
+            for (document, vector) in missing.into_iter().zip(vectors) {
+                document.embedding = vector;
+            }
+        }
+
+        // checked before anything is added, so a failure leaves the store as it was
+        let expected = self
+            .dimensions()
+            .or_else(|| documents.first().map(|document| document.embedding.len()));
+        if let Some(expected) = expected {
+            if let Some(document) = documents
+                .iter()
+                .find(|document| document.embedding.len() != expected)
+            {
+                Err(InternalError::DimensionMismatch(
+                    expected,
+                    document.embedding.len(),
+                ))?
+            }
+        }
+
+        documents
+            .into_iter()
+            .try_for_each(|document| self.add(document))
+    }
+
+    // the `k` documents most similar to the query, best match first. the query needs the same
+    // dimensions as the documents.
+    pub fn search(&self, query: &[f32], k: usize) -> UtilsResult<Vec<SearchResult<'_>>> {
+        self.search_by(query, k, |_| true)
+    }
+
+    pub fn search_filtered(
+        &self,
+        query: &[f32],
+        k: usize,
+        filter: &Filter,
+    ) -> UtilsResult<Vec<SearchResult<'_>>> {
+        self.search_by(query, k, |document| filter.matches(&document.metadata))
+    }
+
+    fn search_by(
+        &self,
+        query: &[f32],
+        k: usize,
+        include: impl Fn(&Document) -> bool,
+    ) -> UtilsResult<Vec<SearchResult<'_>>> {
+        if let Some(dimensions) = self.dimensions() {
+            if query.len() != dimensions {
+                Err(InternalError::DimensionMismatch(dimensions, query.len()))?
+            }
+        }
+
+        let mut results: Vec<SearchResult> = self
+            .documents
+            .iter()
+            .filter(|document| include(document))
+            .map(|document| SearchResult {
+                document,
+                score: self.similarity.score(query, &document.embedding),
+            })
+            .collect();
+
+        results.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+        results.truncate(k);
+        Ok(results)
+    }
+
+    // embeds the query and searches for it
+    pub async fn query(
+        &self,
+        embeddings: &Embeddings,
+        client: &Client,
+        query: &str,
+        k: usize,
+        filter: Option<&Filter>,
+    ) -> UtilsResult<Vec<SearchResult<'_>>> {
+        let vector = embeddings
+            .create_with(client, query)
+            .await?
+            .vectors()
+            .pop()
+            .ok_or(InternalError::NoEmbeddings)?;
+
+        match filter {
+            Some(filter) => self.search_filtered(&vector, k, filter),
+            None => self.search(&vector, k),
+        }
+    }
+
+    // persistence part
+
+    pub fn save_json(&self, path: impl AsRef<Path>) -> UtilsResult<()> {
+        let file = File::create(path).map_err(InternalError::IoError)?;
+        serde_json::to_writer(BufWriter::new(file), self)
+            .map_err(InternalError::SerializationError)?;
+        Ok(())
+    }
+
+    pub fn load_json(path: impl AsRef<Path>) -> UtilsResult<Self> {
+        let file = File::open(path).map_err(InternalError::IoError)?;
+        Ok(serde_json::from_reader(BufReader::new(file))
+            .map_err(InternalError::SerializationError)?)
+    }
+
+    // a compact format that stores the embeddings as raw little endian f32s, several times
+    // smaller than the json
+    pub fn save_binary(&self, path: impl AsRef<Path>) -> UtilsResult<()> {
+        let file = File::create(path).map_err(InternalError::IoError)?;
+        let mut writer = BufWriter::new(file);
+        self.write_binary(&mut writer)
+            .and_then(|_| writer.flush())
+            .map_err(InternalError::IoError)?;
+        Ok(())
+    }
+
+    pub fn load_binary(path: impl AsRef<Path>) -> UtilsResult<Self> {
+        let file = File::open(path).map_err(InternalError::IoError)?;
+        Ok(Self::read_binary(&mut BufReader::new(file)).map_err(InternalError::IoError)?)
+    }
+
+    pub fn write_binary(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&[match self.similarity {
+            Similarity::Cosine => 0,
+            Similarity::DotProduct => 1,
+        }])?;
+        writer.write_all(&(self.dimensions().unwrap_or(0) as u32).to_le_bytes())?;
+        writer.write_all(&(self.documents.len() as u64).to_le_bytes())?;
+
+        for document in &self.documents {
+            write_bytes(writer, document.id.as_bytes())?;
+            write_bytes(writer, document.text.as_bytes())?;
+            write_bytes(writer, &serde_json::to_vec(&document.metadata)?)?;
+            for x in &document.embedding {
+                writer.write_all(&x.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn read_binary(reader: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a vector store file"));
+        }
+
+        let version = read_u32(reader)?;
+        if version != VERSION {
+            return Err(invalid_data(&format!("unsupported version {}", version)));
+        }
+
+        let mut similarity = [0; 1];
+        reader.read_exact(&mut similarity)?;
+        let similarity = match similarity[0] {
+            0 => Similarity::Cosine,
+            1 => Similarity::DotProduct,
+            other => return Err(invalid_data(&format!("unknown similarity {}", other))),
+        };
+
+        let dimensions = read_u32(reader)? as usize;
+        let mut count = [0; 8];
+        reader.read_exact(&mut count)?;
+        let count = u64::from_le_bytes(count);
+
+        let mut store = VectorStore::new().with_similarity(similarity);
+        for _ in 0..count {
+            let id = read_string(reader)?;
+            let text = read_string(reader)?;
+            let metadata = serde_json::from_slice(&read_bytes(reader)?)?;
+
+            let embedding = read_vec(reader, dimensions * 4)?
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect();
+
+            store
+                .add(Document {
+                    id,
+                    text,
+                    metadata,
+                    embedding,
+                })
+                .map_err(|e| invalid_data(&e.to_string()))?;
+        }
+
+        Ok(store)
+    }
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_bytes(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = read_u32(reader)? as usize;
+    read_vec(reader, len)
+}
+
+// lengths come from the file, so the buffer only grows as far as there actually is data
+fn read_vec(reader: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![];
+    reader.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "vector store file is truncated",
+        ));
+    }
+    Ok(bytes)
+}
+
+fn read_string(reader: &mut impl Read) -> io::Result<String> {
+    String::from_utf8(read_bytes(reader)?).map_err(|_| invalid_data("invalid utf-8"))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{Reply, TestServer};
+    use serde_json::json;
+
+    fn store() -> VectorStore {
+        let mut store = VectorStore::new();
+        let documents = [
+            ("a", "apples", [1.0, 0.0, 0.0], "fruit"),
+            ("b", "bananas", [0.8, 0.2, 0.0], "fruit"),
+            ("c", "carrots", [0.0, 1.0, 0.0], "vegetable"),
+            ("d", "dates", [0.0, 0.0, 1.0], "fruit"),
+        ];
+        for (id, text, embedding, kind) in documents {
+            let document = Document::new(id, text)
+                .with_metadata("kind", kind)
+                .with_embedding(embedding.to_vec());
+            store.add(document).unwrap();
+        }
+        store
+    }
+
+    fn ids(results: &[SearchResult]) -> Vec<String> {
+        results.iter().map(|r| r.document.id.clone()).collect()
+    }
+
+    #[test]
+    fn searches_by_similarity() {
+        let store = store();
+
+        let results = store.search(&[1.0, 0.1, 0.0], 2).unwrap();
+        assert_eq!(ids(&results), vec!["a", "b"]);
+
+        let filter = Filter::eq("kind", "fruit");
+        let results = store.search_filtered(&[0.0, 1.0, 0.1], 2, &filter).unwrap();
+        assert_eq!(ids(&results), vec!["b", "d"]);
+    }
+
+    #[test]
+    fn rejects_queries_of_the_wrong_dimension() {
+        let store = store();
+
+        let e = store.search(&[1.0, 0.0], 2).unwrap_err();
+        assert!(matches!(
+            e,
+            Error::Internal(InternalError::DimensionMismatch(3, 2))
+        ));
+
+        // an empty store has no dimensions yet
+        assert!(VectorStore::new().search(&[1.0], 1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_documents_of_the_wrong_dimension() {
+        let mut store = store();
+        let document = Document::new("e", "eggplants").with_embedding(vec![1.0, 0.0]);
+        assert!(store.add(document).is_err());
+        assert!(store.add(Document::new("f", "figs")).is_err());
+        assert_eq!(store.len(), 4);
+    }
+
+    #[test]
+    fn indexes_by_id() {
+        let mut store = store();
+
+        let document = Document::new("b", "blueberries").with_embedding(vec![0.0, 1.0, 1.0]);
+        store.add(document).unwrap();
+        assert_eq!(store.len(), 4);
+        assert_eq!(store.get("b").unwrap().text, "blueberries");
+
+        assert_eq!(store.remove("a").unwrap().text, "apples");
+        assert!(store.get("a").is_none());
+        assert!(store.remove("a").is_none());
+        for id in ["b", "c", "d"] {
+            assert_eq!(store.get(id).unwrap().id, id);
+        }
+
+        assert_eq!(store.remove_where(&Filter::eq("kind", "vegetable")), 1);
+        assert_eq!(store.len(), 2);
+        for id in ["b", "d"] {
+            assert_eq!(store.get(id).unwrap().id, id);
+        }
+    }
+
+    #[test]
+    fn combines_filters() {
+        let store = store();
+        let vegetables = !Filter::eq("kind", "fruit");
+        let vegetables_or_none =
+            Filter::is_in("kind", vec![json!("vegetable")]).or(Filter::eq("kind", "none"));
+
+        let results = store
+            .search_filtered(&[1.0, 1.0, 1.0], 10, &vegetables)
+            .unwrap();
+        assert_eq!(ids(&results), vec!["c"]);
+
+        let results = store
+            .search_filtered(&[1.0, 1.0, 1.0], 10, &vegetables_or_none)
+            .unwrap();
+        assert_eq!(ids(&results), vec!["c"]);
+
+        let missing = Filter::exists("color").and(Filter::eq("kind", "fruit"));
+        assert!(store
+            .search_filtered(&[1.0, 1.0, 1.0], 10, &missing)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn round_trips_through_binary() {
+        let store = store().with_similarity(Similarity::DotProduct);
+
+        let mut bytes = vec![];
+        store.write_binary(&mut bytes).unwrap();
+        let loaded = VectorStore::read_binary(&mut bytes.as_slice()).unwrap();
+
+        assert_eq!(loaded.similarity, Similarity::DotProduct);
+        assert_eq!(loaded.documents(), store.documents());
+        assert_eq!(loaded.get("c").unwrap().text, "carrots");
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let store = store();
+
+        let json = serde_json::to_string(&store).unwrap();
+        let loaded: VectorStore = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(loaded.documents(), store.documents());
+        assert_eq!(loaded.get("d").unwrap().text, "dates");
+    }
+
+    #[test]
+    fn rejects_loaded_documents_of_mixed_dimensions() {
+        let json = json!({
+            "similarity": "cosine",
+            "documents": [
+                { "id": "a", "text": "a", "embedding": [1.0, 0.0] },
+                { "id": "b", "text": "b", "embedding": [1.0] }
+            ]
+        });
+        assert!(serde_json::from_value::<VectorStore>(json).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_and_oversized_binary_files() {
+        let mut bytes = vec![];
+        store().write_binary(&mut bytes).unwrap();
+
+        for len in [0, 3, 8, 20, bytes.len() - 1] {
+            assert!(
+                VectorStore::read_binary(&mut &bytes[..len]).is_err(),
+                "length {}",
+                len
+            );
+        }
+
+        // a header claiming huge documents must fail on the missing data, not allocate it
+        let mut header = MAGIC.to_vec();
+        header.extend(VERSION.to_le_bytes());
+        header.push(0);
+        header.extend(u32::MAX.to_le_bytes());
+        header.extend(u64::MAX.to_le_bytes());
+        header.extend(u32::MAX.to_le_bytes());
+        header.extend(b"abc");
+        assert!(VectorStore::read_binary(&mut header.as_slice()).is_err());
+
+        let mut wrong_magic = bytes.clone();
+        wrong_magic[0] = b'X';
+        assert!(VectorStore::read_binary(&mut wrong_magic.as_slice()).is_err());
+    }
+
+    fn embedding_response(vectors: &[[f32; 3]]) -> Reply {
+        let data: Vec<_> = vectors
+            .iter()
+            .enumerate()
+            .map(|(index, vector)| json!({"object": "embedding", "index": index, "embedding": vector}))
+            .collect();
+        Reply::json(
+            200,
+            &json!({
+                "object": "list",
+                "data": data,
+                "model": "text-embedding-3-small",
+                "usage": {"prompt_tokens": 4, "total_tokens": 4},
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn embeds_documents_without_embeddings() {
+        let server = TestServer::start(vec![embedding_response(&[[0.0, 1.0, 0.0]])]).await;
+        let mut store = VectorStore::new();
+        let documents = vec![
+            Document::new("a", "apples").with_embedding(vec![1.0, 0.0, 0.0]),
+            Document::new("b", "broccoli"),
+        ];
+
+        let embeddings = Embeddings::new("text-embedding-3-small");
+        store
+            .embed_and_add(&embeddings, &server.client(), documents)
+            .await
+            .unwrap();
+
+        assert_eq!(store.get("b").unwrap().embedding, vec![0.0, 1.0, 0.0]);
+        assert_eq!(server.requests()[0]["input"], json!(["broccoli"]));
+    }
+
+    #[tokio::test]
+    async fn missing_vectors_leave_the_store_unchanged() {
+        let server = TestServer::start(vec![embedding_response(&[[0.0, 1.0, 0.0]])]).await;
+        let mut store = store();
+        let documents = vec![Document::new("e", "eggplant"), Document::new("f", "figs")];
+
+        let embeddings = Embeddings::new("text-embedding-3-small");
+        let e = store
+            .embed_and_add(&embeddings, &server.client(), documents)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(e, Error::Internal(InternalError::NoEmbeddings)));
+        assert_eq!(store.len(), 4);
+        assert!(store.get("e").is_none());
+    }
+
+    #[tokio::test]
+    async fn mismatched_dimensions_leave_the_store_unchanged() {
+        let mut store = store();
+        let documents = vec![
+            Document::new("e", "eggplant").with_embedding(vec![0.0, 1.0, 0.0]),
+            Document::new("f", "figs").with_embedding(vec![1.0, 0.0]),
+        ];
+
+        // everything is embedded already, so no request is made
+        let embeddings = Embeddings::new("text-embedding-3-small");
+        let e = store
+            .embed_and_add(&embeddings, &Client::new(), documents)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(
+            e,
+            Error::Internal(InternalError::DimensionMismatch(3, 2))
+        ));
+        assert_eq!(store.len(), 4);
+        assert!(store.get("e").is_none());
+    }
+}
@@ -0,0 +1,112 @@
+use openai_utils::{openai_tool, AiAgent, FunctionCall, FunctionRegistry, ToolFunction};
+use serde_json::json;
+
+/// Adds two numbers
+#[openai_tool]
+fn add_numbers(#[arg(description = "the first number")] a: i64, b: i64) -> i64 {
+    a + b
+}
+
+/// Looks up the weather
+#[openai_tool(name = "weather", description = "Current weather for a city")]
+async fn get_weather(city: String) -> String {
+    format!("sunny in {}", city)
+}
+
+fn call(name: &str, arguments: &str) -> FunctionCall {
+    FunctionCall {
+        name: name.to_string(),
+        arguments: arguments.to_string(),
+    }
+}
+
+#[test]
+fn generates_args_and_tool_types() {
+    let args = AddNumbersArgs { a: 1, b: 2 };
+    assert_eq!(add_numbers(args.a, args.b), 3);
+
+    let _ = GetWeatherArgs {
+        city: "Paris".to_string(),
+    };
+    let _: GetWeatherTool = GetWeatherTool;
+}
+
+#[test]
+fn definition_uses_the_function_name_and_doc_comment() {
+    let definition = AddNumbersTool::definition();
+    assert_eq!(definition.name, "add_numbers");
+    assert_eq!(definition.description.as_deref(), Some("Adds two numbers"));
+    assert_eq!(definition.parameters["required"], json!(["a", "b"]));
+}
+
+#[test]
+fn arg_descriptions_reach_the_schema() {
+    let definition = AddNumbersTool::definition();
+    let properties = &definition.parameters["properties"];
+    assert_eq!(properties["a"]["description"], "the first number");
+    assert!(properties["b"].get("description").is_none());
+}
+
+#[test]
+fn name_and_description_can_be_overridden() {
+    let definition = GetWeatherTool::definition();
+    assert_eq!(definition.name, "weather");
+    assert_eq!(
+        definition.description.as_deref(),
+        Some("Current weather for a city")
+    );
+}
+
+#[tokio::test]
+async fn sync_tools_register_into_the_registry() {
+    let mut registry = FunctionRegistry::new();
+    let definition = AddNumbersTool::register(&mut registry);
+    assert_eq!(definition.name, "add_numbers");
+
+    let result = registry
+        .call(&call("add_numbers", r#"{"a":2,"b":3}"#))
+        .await;
+    assert_eq!(result.unwrap(), "5");
+}
+
+#[tokio::test]
+async fn async_tools_register_into_the_registry() {
+    let mut registry = FunctionRegistry::new();
+    GetWeatherTool::register(&mut registry);
+
+    assert!(registry.contains("weather"));
+    assert!(!registry.contains("get_weather"));
+    let result = registry.call(&call("weather", r#"{"city":"Paris"}"#)).await;
+    assert_eq!(result.unwrap(), "sunny in Paris");
+    assert_eq!(get_weather("Rome".to_string()).await, "sunny in Rome");
+}
+
+#[tokio::test]
+async fn registered_tools_validate_their_arguments() {
+    let mut registry = FunctionRegistry::new();
+    AddNumbersTool::register(&mut registry);
+
+    let e = registry
+        .call(&call("add_numbers", r#"{"a":"two"}"#))
+        .await
+        .unwrap_err();
+    assert_eq!(e.argument_errors().unwrap().violations.len(), 2);
+}
+
+#[tokio::test]
+async fn agents_register_tools() {
+    let mut agent = AiAgent::new("gpt-4o");
+    agent.register_tool::<AddNumbersTool>();
+    agent.register_tool::<GetWeatherTool>();
+
+    let result = agent
+        .call_function(&call("add_numbers", r#"{"a":1,"b":1}"#))
+        .await
+        .unwrap();
+    assert_eq!(result.content.unwrap().text(), "2");
+}
+
+#[test]
+fn rejects_unsupported_functions() {
+    trybuild::TestCases::new().compile_fail("tests/ui/*.rs");
+}
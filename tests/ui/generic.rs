@@ -0,0 +1,8 @@
+use openai_utils::openai_tool;
+
+#[openai_tool]
+fn echo<T: ToString>(value: T) -> String {
+    value.to_string()
+}
+
+fn main() {}
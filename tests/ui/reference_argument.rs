@@ -0,0 +1,8 @@
+use openai_utils::openai_tool;
+
+#[openai_tool]
+fn shout(text: &str) -> String {
+    text.to_uppercase()
+}
+
+fn main() {}
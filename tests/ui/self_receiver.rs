@@ -0,0 +1,12 @@
+use openai_utils::openai_tool;
+
+struct Calculator;
+
+impl Calculator {
+    #[openai_tool]
+    fn add(&self, a: i64, b: i64) -> i64 {
+        a + b
+    }
+}
+
+fn main() {}
@@ -0,0 +1,8 @@
+use openai_utils::openai_tool;
+
+#[openai_tool(title = "add")]
+fn add(a: i64, b: i64) -> i64 {
+    a + b
+}
+
+fn main() {}